@@ -1,5 +1,5 @@
--- PostgreSQL schema for orders table with 19 attributes
--- This table is designed for exporting 1 million records to Excel
+-- Baseline schema: the orders table exported by the demo, and saved report
+-- definitions. IF NOT EXISTS lets it adopt a database set up by hand.
 
 CREATE TABLE IF NOT EXISTS orders (
     id SERIAL PRIMARY KEY,
@@ -29,3 +29,11 @@ CREATE INDEX IF NOT EXISTS idx_orders_order_date ON orders(order_date);
 -- Create index on customer_id for better query performance
 CREATE INDEX IF NOT EXISTS idx_orders_customer_id ON orders(customer_id);
 
+
+-- Saved report definitions (JSON, same shape as the files in REPORTS_DIR).
+-- Rows here override report files with the same name.
+CREATE TABLE IF NOT EXISTS reports (
+    name VARCHAR(100) PRIMARY KEY,
+    definition JSONB NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,3 @@
+-- Exports filtered by status page through the matching rows by id
+-- (WHERE status = ANY(...) AND id > $n ORDER BY id).
+CREATE INDEX IF NOT EXISTS idx_orders_status_id ON orders(status, id);
@@ -0,0 +1,16 @@
+-- Exports, report runs and seeding runs started by the server. A job left
+-- `running` by a server that stopped without finishing it is marked `failed`
+-- by the next server to start; `server_pid` is the backend of the server's
+-- connection, so a job is only taken as dead once that backend is gone.
+CREATE TABLE export_jobs (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    name TEXT,
+    status TEXT NOT NULL DEFAULT 'running',
+    error TEXT,
+    server_pid INTEGER,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_export_jobs_running ON export_jobs(id) WHERE status = 'running';
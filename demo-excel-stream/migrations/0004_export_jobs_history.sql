@@ -0,0 +1,10 @@
+-- Job history for auditing: who asked for which export with what options,
+-- and what came of it. The duration is finished_at - started_at.
+ALTER TABLE export_jobs
+    ADD COLUMN requested_by TEXT,
+    ADD COLUMN parameters JSONB NOT NULL DEFAULT '{}',
+    ADD COLUMN row_count BIGINT,
+    ADD COLUMN file_size BIGINT,
+    ADD COLUMN locations TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX idx_export_jobs_started_at ON export_jobs(started_at DESC, id DESC);
@@ -0,0 +1,6 @@
+-- The server a job runs on, by instance id rather than backend pid: the pid
+-- changes when the server reconnects and may be reused by another backend.
+-- A server's connections carry its instance id in `application_name`, so a
+-- job is taken as dead once no connection in pg_stat_activity has it.
+-- `server_pid` stays for jobs recorded before this column.
+ALTER TABLE export_jobs ADD COLUMN server_instance UUID;
@@ -0,0 +1,662 @@
+//! Authentication and authorization for the HTTP API.
+//!
+//! Callers authenticate with a static API key (`X-API-Key`) or a JWT bearer
+//! token (`Authorization: Bearer …`) signed by a key in a local JWKS file.
+//! Either way they become a `Principal` with a set of roles:
+//!
+//! - `admin` may seed data and export,
+//! - `exporter` may export,
+//! - any other role only selects a redaction policy (see `redaction`).
+
+use crate::config::Config;
+use crate::error::AppError;
+use crate::redaction::RedactionConfig;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
+use actix_web::middleware::Next;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::future::{ready, Ready};
+use std::path::Path;
+use std::sync::Arc;
+
+pub const ADMIN_ROLE: &str = "admin";
+pub const EXPORTER_ROLE: &str = "exporter";
+
+const API_KEY_HEADER: &str = "X-API-Key";
+const ANONYMOUS: &str = "anonymous";
+/// Clock skew tolerated on `exp` / `nbf`, in seconds.
+const LEEWAY_SECS: i64 = 60;
+
+/// What a route requires of its caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// `POST /insert-data`.
+    Seed,
+    /// Exports, reports, schedules and job history.
+    Export,
+}
+
+/// An authenticated caller.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    /// Key name or token subject, for logs.
+    pub name: String,
+    pub roles: Vec<String>,
+}
+
+impl Principal {
+    /// The caller when authentication is disabled: allowed everything, with
+    /// the default redaction policy.
+    fn anonymous() -> Self {
+        Principal {
+            name: ANONYMOUS.to_string(),
+            roles: vec![ADMIN_ROLE.to_string()],
+        }
+    }
+
+    /// Whether authentication is disabled for this caller.
+    pub fn is_anonymous(&self) -> bool {
+        self.name == ANONYMOUS && self.roles == [ADMIN_ROLE]
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    pub fn require(&self, permission: Permission) -> Result<(), AppError> {
+        let allowed = match permission {
+            Permission::Seed => self.has_role(ADMIN_ROLE),
+            Permission::Export => self.has_role(ADMIN_ROLE) || self.has_role(EXPORTER_ROLE),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!(
+                "'{}' may not {}",
+                self.name,
+                match permission {
+                    Permission::Seed => "seed data",
+                    Permission::Export => "export",
+                }
+            )))
+        }
+    }
+
+    /// The first of the caller's roles that has its own redaction policy.
+    pub fn redaction_role<'a>(&'a self, redaction: &RedactionConfig) -> Option<&'a str> {
+        self.roles
+            .iter()
+            .map(String::as_str)
+            .find(|r| redaction.roles.contains_key(*r))
+    }
+}
+
+/// Available to handlers behind `middleware`.
+impl FromRequest for Principal {
+    type Error = AppError;
+    type Future = Ready<Result<Self, AppError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Principal>()
+                .cloned()
+                .ok_or_else(|| AppError::Unauthorized("authentication required".to_string())),
+        )
+    }
+}
+
+/// Actix middleware: authenticate the request or answer 401.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let authenticator = req
+        .app_data::<web::Data<Arc<Authenticator>>>()
+        .ok_or_else(|| AppError::Config("authenticator not configured".to_string()))?
+        .clone();
+    let principal = authenticator.authenticate(req.headers())?;
+    req.extensions_mut().insert(principal);
+    next.call(req).await
+}
+
+/// The configured ways of authenticating.
+pub struct Authenticator {
+    disabled: bool,
+    api_keys: Option<ApiKeys>,
+    jwt: Option<JwtVerifier>,
+}
+
+impl Authenticator {
+    /// Fails when nothing is configured, unless `AUTH_DISABLED=true`.
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let api_keys = config
+            .api_keys_file
+            .as_deref()
+            .map(|path| ApiKeys::load(Path::new(path)))
+            .transpose()?;
+        let jwt = config
+            .jwt_jwks_file
+            .as_deref()
+            .map(|path| JwtVerifier::load(Path::new(path), config))
+            .transpose()?;
+
+        if !config.auth_disabled && api_keys.is_none() && jwt.is_none() {
+            return Err(
+                "set API_KEYS_FILE and/or JWT_JWKS_FILE, or AUTH_DISABLED=true to run without \
+                 authentication"
+                    .to_string(),
+            );
+        }
+        Ok(Authenticator {
+            disabled: config.auth_disabled,
+            api_keys,
+            jwt,
+        })
+    }
+
+    /// Short description for the startup log.
+    pub fn describe(&self) -> String {
+        if self.disabled {
+            return "disabled".to_string();
+        }
+        let mut methods = Vec::new();
+        if let Some(keys) = &self.api_keys {
+            methods.push(format!("{} API key(s)", keys.keys.len()));
+        }
+        if let Some(jwt) = &self.jwt {
+            methods.push(format!("JWT ({} key(s))", jwt.keys.len()));
+        }
+        methods.join(", ")
+    }
+
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, AppError> {
+        if self.disabled {
+            return Ok(Principal::anonymous());
+        }
+
+        if let Some(key) = header(headers, API_KEY_HEADER)? {
+            return self
+                .api_keys
+                .as_ref()
+                .and_then(|keys| keys.verify(key))
+                .ok_or_else(|| AppError::Unauthorized("invalid API key".to_string()));
+        }
+
+        if let Some(value) = header(headers, AUTHORIZATION.as_str())? {
+            let token = value
+                .strip_prefix("Bearer ")
+                .ok_or_else(|| AppError::Unauthorized("expected a Bearer token".to_string()))?;
+            let jwt = self.jwt.as_ref().ok_or_else(|| {
+                AppError::Unauthorized("bearer tokens are not accepted".to_string())
+            })?;
+            return jwt
+                .verify(token.trim(), chrono::Utc::now().timestamp())
+                .map_err(|e| AppError::Unauthorized(format!("invalid token: {}", e)));
+        }
+
+        Err(AppError::Unauthorized(
+            "authentication required".to_string(),
+        ))
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<Option<&'a str>, AppError> {
+    headers
+        .get(name)
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|_| AppError::Unauthorized(format!("invalid {} header", name)))
+        })
+        .transpose()
+}
+
+/// Static API keys, stored as SHA-256 hashes in `API_KEYS_FILE`:
+///
+/// ```toml
+/// [keys.ci]
+/// sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+/// roles = ["exporter"]
+/// ```
+#[derive(Debug, Deserialize)]
+struct ApiKeys {
+    keys: BTreeMap<String, ApiKey>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiKey {
+    sha256: String,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+impl ApiKeys {
+    fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let keys: ApiKeys =
+            toml::from_str(&text).map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        for (name, key) in &keys.keys {
+            if hex::decode(&key.sha256).map(|h| h.len()) != Ok(32) {
+                return Err(format!(
+                    "{}: key '{}' needs a hex SHA-256 `sha256`",
+                    path.display(),
+                    name
+                ));
+            }
+        }
+        Ok(keys)
+    }
+
+    fn verify(&self, key: &str) -> Option<Principal> {
+        let digest = Sha256::digest(key.as_bytes());
+        self.keys
+            .iter()
+            .find(|(_, k)| {
+                hex::decode(&k.sha256).is_ok_and(|expected| constant_time_eq(&expected, &digest))
+            })
+            .map(|(name, k)| Principal {
+                name: name.clone(),
+                roles: k.roles.clone(),
+            })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// One key of a JWKS document. Supported: RSA (RS256), EC P-256 (ES256) and
+/// symmetric `oct` keys (HS256).
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    alg: Option<String>,
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+    #[serde(default)]
+    k: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+/// Verifies bearer tokens against the keys in `JWT_JWKS_FILE`.
+struct JwtVerifier {
+    keys: Vec<Jwk>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    roles_claim: String,
+}
+
+impl JwtVerifier {
+    fn load(path: &Path, config: &Config) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let set: JwkSet = serde_json::from_str(&text)
+            .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        if set.keys.is_empty() {
+            return Err(format!("{} contains no keys", path.display()));
+        }
+        Ok(JwtVerifier {
+            keys: set.keys,
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+            roles_claim: config.jwt_roles_claim.clone(),
+        })
+    }
+
+    fn verify(&self, token: &str, now: i64) -> Result<Principal, String> {
+        let mut parts = token.split('.');
+        let (header, payload, sig) = match (parts.next(), parts.next(), parts.next(), parts.next())
+        {
+            (Some(h), Some(p), Some(s), None) => (h, p, s),
+            _ => return Err("malformed token".to_string()),
+        };
+        let header: JwtHeader =
+            serde_json::from_slice(&decode(header)?).map_err(|_| "malformed header".to_string())?;
+        let signature = decode(sig)?;
+
+        let key = self
+            .keys
+            .iter()
+            .find(|k| match (&header.kid, &k.kid) {
+                (Some(wanted), Some(kid)) => wanted == kid,
+                (None, _) => self.keys.len() == 1,
+                _ => false,
+            })
+            .ok_or_else(|| "unknown signing key".to_string())?;
+        if key.alg.as_deref().is_some_and(|alg| alg != header.alg) {
+            return Err(format!("key does not allow {}", header.alg));
+        }
+
+        let message = &token[..header_len(token)];
+        verify_signature(key, &header.alg, message.as_bytes(), &signature)?;
+
+        let claims: serde_json::Value = serde_json::from_slice(&decode(payload)?)
+            .map_err(|_| "malformed claims".to_string())?;
+        self.check_claims(&claims, now)?;
+
+        let roles = match claims.get(&self.roles_claim) {
+            Some(serde_json::Value::Array(values)) => values
+                .iter()
+                .filter_map(|v| v.as_str().map(str::to_string))
+                .collect(),
+            Some(serde_json::Value::String(value)) => {
+                value.split_whitespace().map(str::to_string).collect()
+            }
+            _ => Vec::new(),
+        };
+        Ok(Principal {
+            name: claims
+                .get("sub")
+                .and_then(|s| s.as_str())
+                .unwrap_or("unknown")
+                .to_string(),
+            roles,
+        })
+    }
+
+    fn check_claims(&self, claims: &serde_json::Value, now: i64) -> Result<(), String> {
+        let exp = claims
+            .get("exp")
+            .and_then(|v| v.as_i64())
+            .ok_or_else(|| "missing exp".to_string())?;
+        if now > exp + LEEWAY_SECS {
+            return Err("expired".to_string());
+        }
+        if let Some(nbf) = claims.get("nbf").and_then(|v| v.as_i64()) {
+            if now + LEEWAY_SECS < nbf {
+                return Err("not yet valid".to_string());
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(|v| v.as_str()) != Some(issuer.as_str()) {
+                return Err("wrong issuer".to_string());
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match claims.get("aud") {
+                Some(serde_json::Value::String(aud)) => aud == audience,
+                Some(serde_json::Value::Array(auds)) => {
+                    auds.iter().any(|a| a.as_str() == Some(audience.as_str()))
+                }
+                _ => false,
+            };
+            if !matches {
+                return Err("wrong audience".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Length of `header.payload`, the signed part of a token.
+fn header_len(token: &str) -> usize {
+    token.rfind('.').unwrap_or(token.len())
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, String> {
+    BASE64_URL
+        .decode(part)
+        .map_err(|_| "malformed base64".to_string())
+}
+
+fn jwk_field(value: &Option<String>, name: &str) -> Result<Vec<u8>, String> {
+    decode(
+        value
+            .as_deref()
+            .ok_or_else(|| format!("key is missing `{}`", name))?,
+    )
+}
+
+fn verify_signature(key: &Jwk, alg: &str, message: &[u8], sig: &[u8]) -> Result<(), String> {
+    let valid = match (alg, key.kty.as_str()) {
+        ("RS256", "RSA") => RsaPublicKeyComponents {
+            n: jwk_field(&key.n, "n")?,
+            e: jwk_field(&key.e, "e")?,
+        }
+        .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+        .is_ok(),
+        ("ES256", "EC") if key.crv.as_deref() == Some("P-256") => {
+            let mut point = vec![0x04];
+            point.extend(jwk_field(&key.x, "x")?);
+            point.extend(jwk_field(&key.y, "y")?);
+            UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(message, sig)
+                .is_ok()
+        }
+        ("HS256", "oct") => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(&jwk_field(&key.k, "k")?)
+                .expect("HMAC accepts keys of any length");
+            mac.update(message);
+            mac.verify_slice(sig).is_ok()
+        }
+        _ => {
+            return Err(format!(
+                "unsupported algorithm {} for a {} key",
+                alg, key.kty
+            ))
+        }
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err("bad signature".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair};
+
+    const NOW: i64 = 1_790_000_000;
+    const HS_SECRET: &[u8] = b"a shared secret of at least 32 bytes";
+
+    fn oct_key(kid: &str) -> Jwk {
+        Jwk {
+            kty: "oct".to_string(),
+            kid: Some(kid.to_string()),
+            alg: Some("HS256".to_string()),
+            crv: None,
+            n: None,
+            e: None,
+            x: None,
+            y: None,
+            k: Some(BASE64_URL.encode(HS_SECRET)),
+        }
+    }
+
+    fn verifier(keys: Vec<Jwk>) -> JwtVerifier {
+        JwtVerifier {
+            keys,
+            issuer: Some("https://issuer.example".to_string()),
+            audience: Some("exports".to_string()),
+            roles_claim: "roles".to_string(),
+        }
+    }
+
+    fn claims() -> serde_json::Value {
+        serde_json::json!({
+            "sub": "alice",
+            "iss": "https://issuer.example",
+            "aud": ["other", "exports"],
+            "exp": NOW + 300,
+            "roles": ["exporter", "finance"],
+        })
+    }
+
+    fn unsigned(alg: &str, kid: &str, claims: &serde_json::Value) -> String {
+        let header = serde_json::json!({ "alg": alg, "kid": kid, "typ": "JWT" });
+        format!(
+            "{}.{}",
+            BASE64_URL.encode(header.to_string()),
+            BASE64_URL.encode(claims.to_string())
+        )
+    }
+
+    fn hs256(kid: &str, claims: &serde_json::Value) -> String {
+        let message = unsigned("HS256", kid, claims);
+        let mut mac = Hmac::<Sha256>::new_from_slice(HS_SECRET).unwrap();
+        mac.update(message.as_bytes());
+        format!(
+            "{}.{}",
+            message,
+            BASE64_URL.encode(mac.finalize().into_bytes())
+        )
+    }
+
+    #[test]
+    fn hs256_token_yields_principal() {
+        let principal = verifier(vec![oct_key("k1")])
+            .verify(&hs256("k1", &claims()), NOW)
+            .unwrap();
+        assert_eq!(principal.name, "alice");
+        assert_eq!(principal.roles, ["exporter", "finance"]);
+        assert!(principal.require(Permission::Export).is_ok());
+        assert!(principal.require(Permission::Seed).is_err());
+    }
+
+    #[test]
+    fn es256_token_verifies_against_jwk() {
+        let rng = SystemRandom::new();
+        let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &rng).unwrap();
+        // Uncompressed point: 0x04 || x || y
+        let point = pair.public_key().as_ref();
+        let key = Jwk {
+            kty: "EC".to_string(),
+            kid: Some("ec".to_string()),
+            alg: None,
+            crv: Some("P-256".to_string()),
+            n: None,
+            e: None,
+            x: Some(BASE64_URL.encode(&point[1..33])),
+            y: Some(BASE64_URL.encode(&point[33..])),
+            k: None,
+        };
+        let message = unsigned("ES256", "ec", &claims());
+        let sig = pair.sign(&rng, message.as_bytes()).unwrap();
+        let token = format!("{}.{}", message, BASE64_URL.encode(sig.as_ref()));
+
+        let verifier = verifier(vec![oct_key("k1"), key]);
+        assert_eq!(verifier.verify(&token, NOW).unwrap().name, "alice");
+
+        let mut tampered = claims();
+        tampered["roles"] = serde_json::json!(["admin"]);
+        let forged = format!(
+            "{}.{}",
+            unsigned("ES256", "ec", &tampered),
+            BASE64_URL.encode(sig.as_ref())
+        );
+        assert_eq!(verifier.verify(&forged, NOW).unwrap_err(), "bad signature");
+    }
+
+    #[test]
+    fn rejects_bad_tokens() {
+        let verifier = verifier(vec![oct_key("k1")]);
+        let rejected = |claims: serde_json::Value| {
+            verifier.verify(&hs256("k1", &claims), NOW).unwrap_err()
+        };
+
+        let mut expired = claims();
+        expired["exp"] = (NOW - LEEWAY_SECS - 1).into();
+        assert_eq!(rejected(expired), "expired");
+
+        let mut early = claims();
+        early["nbf"] = (NOW + LEEWAY_SECS + 1).into();
+        assert_eq!(rejected(early), "not yet valid");
+
+        let mut issuer = claims();
+        issuer["iss"] = "https://evil.example".into();
+        assert_eq!(rejected(issuer), "wrong issuer");
+
+        let mut audience = claims();
+        audience["aud"] = "other".into();
+        assert_eq!(rejected(audience), "wrong audience");
+
+        let mut no_exp = claims();
+        no_exp.as_object_mut().unwrap().remove("exp");
+        assert_eq!(rejected(no_exp), "missing exp");
+
+        assert_eq!(
+            verifier.verify(&hs256("k2", &claims()), NOW).unwrap_err(),
+            "unknown signing key"
+        );
+        // The key pins its algorithm; `none` and others are refused
+        let none = format!("{}.", unsigned("none", "k1", &claims()));
+        assert_eq!(
+            verifier.verify(&none, NOW).unwrap_err(),
+            "key does not allow none"
+        );
+        let zeroed = format!(
+            "{}.{}",
+            unsigned("HS256", "k1", &claims()),
+            BASE64_URL.encode([0u8; 32])
+        );
+        assert_eq!(verifier.verify(&zeroed, NOW).unwrap_err(), "bad signature");
+        assert_eq!(
+            verifier.verify("a.b", NOW).unwrap_err(),
+            "malformed token"
+        );
+    }
+
+    #[test]
+    fn roles_claim_may_be_a_string() {
+        let mut claims = claims();
+        claims["roles"] = "admin exporter".into();
+        let principal = verifier(vec![oct_key("k1")])
+            .verify(&hs256("k1", &claims), NOW)
+            .unwrap();
+        assert_eq!(principal.roles, ["admin", "exporter"]);
+    }
+
+    #[test]
+    fn api_keys_match_by_hash() {
+        let keys: ApiKeys = toml::from_str(
+            r#"
+            [keys.ci]
+            sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+            roles = ["exporter"]
+            "#,
+        )
+        .unwrap();
+        let principal = keys.verify("test").unwrap();
+        assert_eq!(principal.name, "ci");
+        assert_eq!(principal.roles, ["exporter"]);
+        assert!(keys.verify("Test").is_none());
+        assert!(keys.verify("").is_none());
+    }
+}
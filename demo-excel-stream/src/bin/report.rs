@@ -0,0 +1,71 @@
+//! Command-line runner for saved report definitions.
+//!
+//!   cargo run --bin report -- list
+//!   cargo run --bin report -- run <name> [output_dir]
+
+use clap::{Parser, Subcommand};
+use demo_excel_stream::{config, db, reports, telemetry};
+use dotenv::dotenv;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Run saved report definitions.
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    #[command(flatten)]
+    config: config::ConfigArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the reports in the catalog
+    List,
+    /// Run one report
+    Run {
+        name: String,
+        /// Where to write the files (default: EXPORT_DIR)
+        output_dir: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv().ok();
+
+    let cli = Cli::parse();
+    let config = config::Config::load_or_exit(&cli.config);
+    let _telemetry = telemetry::init(&config.telemetry, "report");
+    let pool = Arc::new(db::DbPool::new(&config).await?);
+    let catalog = reports::ReportCatalog::load(&pool, &config).await?;
+
+    match cli.command {
+        Command::List => {
+            for report in catalog.list() {
+                println!(
+                    "{:<24} {}",
+                    report.name,
+                    report.description.as_deref().unwrap_or("")
+                );
+            }
+            for invalid in catalog.invalid() {
+                eprintln!("Skipped {}: {}", invalid.source, invalid.error);
+            }
+        }
+        Command::Run { name, output_dir } => {
+            let report = catalog
+                .get(&name)
+                .ok_or_else(|| format!("Unknown report '{}'", name))?;
+            let output_dir = output_dir.unwrap_or_else(|| PathBuf::from(&config.export_dir));
+
+            println!("Running report '{}'...", report.name);
+            let files = reports::run_report(pool.clone(), &config, report, &output_dir).await?;
+            for file in files {
+                println!("Wrote {} ({} rows)", file.path.display(), file.rows);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,74 +1,791 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use demo_excel_stream::{config, db, error, export, insert_data};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::dev::ServerHandle;
+use actix_web::middleware::from_fn;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use demo_excel_stream::auth::{self, Permission, Principal};
+use demo_excel_stream::jobs::{self, JobKind, JobOutput, JobRequest, Jobs};
+use demo_excel_stream::storage::{self, ExportStorage, LocalStorage};
+use demo_excel_stream::{
+    config, db, error, export, health, insert_data, limits, metrics, migrate, redaction, reports,
+    request_id, retention, scheduler, style, telemetry, template,
+};
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+
+/// How long cancelled jobs get to clean up before the server stops anyway.
+const CANCEL_WAIT: Duration = Duration::from_secs(10);
+
+/// Per-client identity for the export limits.
+fn client_id(req: &HttpRequest, principal: &Principal) -> String {
+    let peer = req.peer_addr().map(|addr| addr.ip().to_string());
+    limits::client_id(principal, peer.as_deref())
+}
 
 async fn insert_data_handler(
+    req: HttpRequest,
+    principal: Principal,
     pool: web::Data<Arc<db::DbPool>>,
+    limiter: web::Data<Arc<limits::ExportLimiter>>,
+    jobs: web::Data<Arc<Jobs>>,
 ) -> Result<impl Responder, error::AppError> {
-    println!("Starting data insertion...");
-    insert_data::insert_test_data(pool.get_ref().clone(), 1_596_496).await?;
+    principal.require(Permission::Seed)?;
+    // Seeding loads the database like an export does.
+    let _permit = limiter.acquire(&client_id(&req, &principal)).await?;
+    tracing::info!(principal = %principal.name, "Starting data insertion");
+    let total_rows = 1_596_496;
+    let seed = async {
+        let inserted = insert_data::insert_test_data(pool.get_ref().clone(), total_rows).await?;
+        let output = JobOutput {
+            rows: Some(inserted),
+            ..JobOutput::default()
+        };
+        Ok(((), output))
+    };
+    let request = JobRequest {
+        kind: JobKind::Seed,
+        name: None,
+        requested_by: principal.name.clone(),
+        parameters: serde_json::json!({ "total_rows": total_rows }),
+    };
+    jobs.run(request, seed).await?;
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "message": "Successfully inserted 1,000,000+ rows"
     })))
 }
 
+/// Also recorded as the job's parameters.
+#[derive(Deserialize, Serialize)]
+struct ExportParams {
+    /// `all`, `none` or a comma-separated list of style options.
+    style: Option<String>,
+    /// Append summary sheets (by status, country, month, payment method).
+    summary: Option<bool>,
+    /// Add charts to the summary sheets (implies `summary`).
+    charts: Option<bool>,
+    /// Name of a template in `TEMPLATES_DIR` to fill instead of a blank workbook.
+    template: Option<String>,
+    /// Defined name or `{{marker}}` text locating the data in the template.
+    anchor: Option<String>,
+    /// Extra redaction, e.g. `customer_email:mask,postal_code:truncate`.
+    redact: Option<String>,
+}
+
+/// Passwords come from headers rather than the query string, which ends up in
+/// access logs.
+fn header_value(req: &HttpRequest, name: &str) -> Result<Option<String>, error::AppError> {
+    req.headers()
+        .get(name)
+        .map(|value| {
+            value
+                .to_str()
+                .map(str::to_string)
+                .map_err(|_| error::AppError::BadRequest(format!("invalid {} header", name)))
+        })
+        .transpose()
+}
+
+#[allow(clippy::too_many_arguments)] // one extractor per dependency
 async fn export_handler(
+    req: HttpRequest,
+    principal: Principal,
     pool: web::Data<Arc<db::DbPool>>,
     config: web::Data<config::Config>,
+    storage: web::Data<Arc<dyn ExportStorage>>,
+    limiter: web::Data<Arc<limits::ExportLimiter>>,
+    jobs: web::Data<Arc<Jobs>>,
+    params: web::Query<ExportParams>,
 ) -> Result<impl Responder, error::AppError> {
-    println!("Starting export...");
-    let file_path = export::export_to_excel(pool.get_ref().clone(), &config, None).await?;
+    principal.require(Permission::Export)?;
+    let style = match &params.style {
+        Some(value) => style::StyleOptions::parse(value).map_err(error::AppError::BadRequest)?,
+        None => config.export_style,
+    };
+
+    let redact = match &params.redact {
+        Some(value) => {
+            redaction::RedactionPolicy::parse(value).map_err(error::AppError::BadRequest)?
+        }
+        None => redaction::RedactionPolicy::default(),
+    };
+
+    let _permit = limiter.acquire(&client_id(&req, &principal)).await?;
+    tracing::info!(principal = %principal.name, "Starting export");
+    let template = match params.template.as_ref().or(config.export_template.as_ref()) {
+        Some(name) => Some(template::resolve(
+            Path::new(&config.templates_dir),
+            name,
+            params
+                .anchor
+                .as_deref()
+                .or(config.export_template_anchor.as_deref()),
+        )?),
+        None => None,
+    };
+    let spec = export::ExportSpec {
+        style,
+        summary: params.summary.unwrap_or(config.export_summary),
+        charts: params.charts.unwrap_or(config.export_charts),
+        template,
+        password: header_value(&req, "X-Export-Password")?.or(config.export_password.clone()),
+        sheet_password: header_value(&req, "X-Sheet-Password")?
+            .or(config.export_sheet_password.clone()),
+        role: principal
+            .redaction_role(&config.redaction)
+            .map(str::to_string),
+        redact,
+        ..export::ExportSpec::default()
+    };
+    let export = async {
+        let file = export::export_query(pool.get_ref().clone(), &config, &spec, None).await?;
+        let published =
+            storage::publish(storage.get_ref().as_ref(), &file.path, config.download_url_ttl)
+                .await?;
+        let mut output = JobOutput::default();
+        output.add(&file, &published.stored);
+        Ok((published, output))
+    };
+    let mut parameters = serde_json::to_value(&*params).unwrap_or_default();
+    parameters["role"] = serde_json::json!(spec.role);
+    parameters["encrypted"] = serde_json::json!(spec.password.is_some());
+    let request = JobRequest {
+        kind: JobKind::Export,
+        name: None,
+        requested_by: principal.name.clone(),
+        parameters,
+    };
+    let published = jobs.run(request, export).await?;
 
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "message": "Export completed",
-        "file_path": file_path.to_string_lossy()
+        "file_path": published.stored.location,
+        "size": published.stored.size,
+        "download_url": published.download_url,
+        "expires_at": published.expires_at
+    })))
+}
+
+async fn list_reports_handler(
+    principal: Principal,
+    pool: web::Data<Arc<db::DbPool>>,
+    config: web::Data<config::Config>,
+) -> Result<impl Responder, error::AppError> {
+    principal.require(Permission::Export)?;
+    let catalog = reports::ReportCatalog::load(&pool, &config).await?;
+    let reports: Vec<_> = catalog.list().collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "reports": reports,
+        "invalid": catalog.invalid()
+    })))
+}
+
+#[allow(clippy::too_many_arguments)] // one extractor per dependency
+async fn run_report_handler(
+    req: HttpRequest,
+    principal: Principal,
+    pool: web::Data<Arc<db::DbPool>>,
+    config: web::Data<config::Config>,
+    storage: web::Data<Arc<dyn ExportStorage>>,
+    limiter: web::Data<Arc<limits::ExportLimiter>>,
+    scheduler: web::Data<Arc<scheduler::Scheduler>>,
+    jobs: web::Data<Arc<Jobs>>,
+    name: web::Path<String>,
+) -> Result<impl Responder, error::AppError> {
+    principal.require(Permission::Export)?;
+    let catalog = reports::ReportCatalog::load(&pool, &config).await?;
+    let mut report = catalog
+        .get(&name)
+        .ok_or_else(|| error::AppError::NotFound(format!("report '{}'", name)))?
+        .clone();
+    if scheduler.is_running(&report.name) {
+        return Err(error::AppError::Conflict(format!(
+            "report '{}' is already running on its schedule",
+            report.name
+        )));
+    }
+    // The report's own role must not reveal more than the caller may see.
+    let caller_policy = config
+        .redaction
+        .policy_for(principal.redaction_role(&config.redaction));
+    report.redact = report.redact.tighten(&caller_policy);
+
+    let _permit = limiter.acquire(&client_id(&req, &principal)).await?;
+    tracing::info!(report = %report.name, principal = %principal.name, "Running report");
+    let output_dir = std::path::PathBuf::from(&config.export_dir);
+    let run = async {
+        let files =
+            reports::run_report(pool.get_ref().clone(), &config, &report, &output_dir).await?;
+        let mut published = Vec::with_capacity(files.len());
+        let mut output = JobOutput::default();
+        for file in files {
+            let stored =
+                storage::publish(storage.get_ref().as_ref(), &file.path, config.download_url_ttl)
+                    .await?;
+            output.add(&file, &stored.stored);
+            published.push(stored);
+        }
+        Ok((published, output))
+    };
+    let request = JobRequest {
+        kind: JobKind::Report,
+        name: Some(report.name.clone()),
+        requested_by: principal.name.clone(),
+        parameters: serde_json::json!({ "report": &report }),
+    };
+    let published = jobs.run(request, run).await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Report completed",
+        "report": report.name,
+        "files": published
     })))
 }
 
+async fn schedules_handler(
+    principal: Principal,
+    pool: web::Data<Arc<db::DbPool>>,
+    config: web::Data<config::Config>,
+    scheduler: web::Data<Arc<scheduler::Scheduler>>,
+) -> Result<impl Responder, error::AppError> {
+    principal.require(Permission::Export)?;
+    let catalog = reports::ReportCatalog::load(&pool, &config).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "schedules": scheduler.schedules(&catalog),
+        "runs": scheduler.history()
+    })))
+}
+
+#[derive(Deserialize)]
+struct ExportsQuery {
+    /// `running`, `succeeded`, `failed` or `cancelled`.
+    status: Option<String>,
+    /// RFC 3339 time or `YYYY-MM-DD`; jobs started at or after it.
+    from: Option<String>,
+    /// RFC 3339 time or `YYYY-MM-DD` (inclusive); jobs started before it.
+    to: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl ExportsQuery {
+    /// The filter for these parameters; `requested_by` limits it to one
+    /// principal's jobs.
+    fn filter(&self, requested_by: Option<String>) -> Result<jobs::JobFilter, error::AppError> {
+        let bad_request = error::AppError::BadRequest;
+        let limit = self.limit.unwrap_or(jobs::DEFAULT_PAGE_SIZE);
+        if !(1..=jobs::MAX_PAGE_SIZE).contains(&limit) {
+            return Err(bad_request(format!(
+                "limit must be between 1 and {}",
+                jobs::MAX_PAGE_SIZE
+            )));
+        }
+        Ok(jobs::JobFilter {
+            status: self
+                .status
+                .as_deref()
+                .map(jobs::JobStatus::parse)
+                .transpose()
+                .map_err(bad_request)?,
+            from: self
+                .from
+                .as_deref()
+                .map(|v| jobs::parse_time(v, false))
+                .transpose()
+                .map_err(bad_request)?,
+            to: self
+                .to
+                .as_deref()
+                .map(|v| jobs::parse_time(v, true))
+                .transpose()
+                .map_err(bad_request)?,
+            requested_by,
+            limit,
+            offset: self.offset.unwrap_or(0).max(0),
+        })
+    }
+}
+
+/// Job history. Admins see every job, other callers only their own.
+async fn list_exports_handler(
+    principal: Principal,
+    jobs: web::Data<Arc<Jobs>>,
+    query: web::Query<ExportsQuery>,
+) -> Result<impl Responder, error::AppError> {
+    principal.require(Permission::Export)?;
+    let filter =
+        query.filter((!principal.has_role(auth::ADMIN_ROLE)).then(|| principal.name.clone()))?;
+    Ok(HttpResponse::Ok().json(jobs.list(&filter).await?))
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    expires: i64,
+    signature: String,
+}
+
+async fn download_handler(
+    req: HttpRequest,
+    local: web::Data<Arc<LocalStorage>>,
+    key: web::Path<String>,
+    query: web::Query<DownloadQuery>,
+) -> Result<HttpResponse, error::AppError> {
+    if !local.verify(&key, query.expires, &query.signature) {
+        return Err(error::AppError::Forbidden(
+            "invalid or expired download link".to_string(),
+        ));
+    }
+
+    let path = local
+        .resolve(&key)
+        .ok_or_else(|| error::AppError::NotFound(format!("export '{}'", key)))?;
+    let file = actix_files::NamedFile::open_async(path)
+        .await?
+        .set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(key.into_inner())],
+        });
+
+    Ok(file.into_response(&req))
+}
+
+/// Routes that require authentication and count against the rate limit.
+/// The middleware wraps each resource rather than a scope, so a path that
+/// matches none of them still reaches the 404 handler.
+fn protected_routes(cfg: &mut web::ServiceConfig) {
+    let routes = [
+        ("/insert-data", web::post().to(insert_data_handler)),
+        ("/export", web::get().to(export_handler)),
+        ("/exports", web::get().to(list_exports_handler)),
+        ("/reports", web::get().to(list_reports_handler)),
+        ("/reports/{name}/run", web::post().to(run_report_handler)),
+        ("/schedules", web::get().to(schedules_handler)),
+    ];
+    for (path, route) in routes {
+        cfg.service(
+            web::resource(path)
+                // The last `wrap` runs first: authenticate, then rate limit.
+                .wrap(from_fn(limits::rate_limit))
+                .wrap(from_fn(auth::middleware))
+                .route(route),
+        );
+    }
+}
+
+async fn not_found_handler(req: HttpRequest) -> Result<HttpResponse, error::AppError> {
+    Err(error::AppError::NotFound(format!("no route for {}", req.path())))
+}
+
+/// Liveness: the process is up and serving requests.
 async fn health_handler() -> impl Responder {
     HttpResponse::Ok().json(serde_json::json!({
         "status": "ok"
     }))
 }
 
+/// Readiness: `503` while the database, the `orders` table or export disk
+/// space is not usable, and once the server is shutting down.
+async fn readiness_handler(
+    pool: web::Data<Arc<db::DbPool>>,
+    config: web::Data<config::Config>,
+    limiter: web::Data<Arc<limits::ExportLimiter>>,
+    scheduler: web::Data<Arc<scheduler::Scheduler>>,
+    jobs: web::Data<Arc<Jobs>>,
+) -> impl Responder {
+    let readiness = health::readiness(&pool, &config, &limiter, &scheduler, &jobs).await;
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+/// Prometheus scrape endpoint.
+async fn metrics_handler() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics::metrics().render())
+}
+
+/// HTTP API for exports, reports and schedules.
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Apply pending database migrations before serving (same as
+    /// MIGRATE_ON_STARTUP=true)
+    #[arg(long)]
+    migrate: bool,
+    #[command(flatten)]
+    config: config::ConfigArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Apply pending database migrations and exit
+    Migrate {
+        /// List applied and pending migrations without applying any
+        #[arg(long)]
+        status: bool,
+    },
+}
+
+/// `server migrate`: apply pending migrations, or with `status`, list them.
+async fn migrate_command(pool: &db::DbPool, status_only: bool) -> Result<(), error::AppError> {
+    let mut client = pool.get_client().await?;
+    if !status_only {
+        let applied = migrate::run(&mut client).await?;
+        if applied.is_empty() {
+            println!("Database is up to date");
+        } else {
+            println!("Applied {} migration(s)", applied.len());
+        }
+    }
+    let status = migrate::status(&client).await?;
+    for migration in migrate::MIGRATIONS {
+        let state = if status.applied.contains(&migration.version) {
+            "applied"
+        } else {
+            "pending"
+        };
+        println!("{:04} {:<32} {}", migration.version, migration.name, state);
+    }
+    for version in &status.unknown {
+        println!("{:04} {:<32} applied by a newer release", version, "?");
+    }
+    Ok(())
+}
+
+/// Apply migrations if asked to, otherwise warn about pending ones.
+async fn migrate_on_startup(pool: &db::DbPool, apply: bool) -> Result<(), error::AppError> {
+    let mut client = pool.get_client().await?;
+    if apply {
+        let applied = migrate::run(&mut client).await?;
+        tracing::info!("Migrations: {} applied at startup", applied.len());
+    }
+    let status = migrate::status(&client).await?;
+    if !status.pending.is_empty() {
+        tracing::warn!(
+            pending = ?status.pending,
+            "Database schema is behind: run `server migrate` or start with --migrate"
+        );
+    }
+    if !status.unknown.is_empty() {
+        tracing::warn!(
+            unknown = ?status.unknown,
+            "Database has migrations this release does not know; is an older build running?"
+        );
+    }
+    Ok(())
+}
+
+/// Resolves on SIGINT (Ctrl-C) or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// On the first signal, refuse new jobs and give running ones `grace` to
+/// finish; then (or on a second signal) cancel the rest and stop the server.
+async fn shutdown_gracefully(server: ServerHandle, jobs: Arc<Jobs>, grace: Duration) {
+    shutdown_signal().await;
+    jobs.drain();
+    let running = jobs.running();
+    if running > 0 {
+        tracing::info!(
+            running,
+            "Shutting down: waiting up to {}s for running jobs",
+            grace.as_secs()
+        );
+        let finished = tokio::select! {
+            idle = tokio::time::timeout(grace, jobs.wait_idle()) => idle.is_ok(),
+            _ = shutdown_signal() => false,
+        };
+        if !finished {
+            tracing::warn!(
+                running = jobs.running(),
+                "Shutting down: cancelling unfinished jobs"
+            );
+            jobs.cancel();
+            let _ = tokio::time::timeout(CANCEL_WAIT, jobs.wait_idle()).await;
+        }
+    } else {
+        tracing::info!("Shutting down");
+    }
+    server.stop(true).await;
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
 
-    let config = config::Config::from_env().map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::Other, format!("Config error: {}", e))
-    })?;
+    let cli = Cli::parse();
+    let config = config::Config::load_or_exit(&cli.config);
+    let _telemetry = telemetry::init(&config.telemetry, "server");
 
-    println!("Connecting to database: {}", config.database_url);
+    tracing::info!(
+        "Connecting to database: {}",
+        telemetry::redact_credentials(&config.database_url)
+    );
     let pool = Arc::new(
         db::DbPool::new(&config)
             .await
             .map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Database connection error: {}", e),
-                )
+                std::io::Error::other(format!("Database connection error: {}", e))
             })?,
     );
 
+    if let Some(Command::Migrate { status }) = cli.command {
+        return migrate_command(&pool, status)
+            .await
+            .map_err(|e| std::io::Error::other(format!("Migration failed: {}", e)));
+    }
+    migrate_on_startup(&pool, cli.migrate || config.migrate_on_startup)
+        .await
+        .map_err(|e| std::io::Error::other(format!("Migration failed: {}", e)))?;
+
+    let jobs = Jobs::new(pool.clone(), config.export_retry_after);
+    match jobs.recover().await {
+        Ok(interrupted) => {
+            for job in interrupted {
+                tracing::warn!(
+                    job_id = job.id,
+                    kind = %job.kind,
+                    name = job.name.as_deref(),
+                    started_at = %job.started_at.to_rfc3339(),
+                    "Job was interrupted by a previous shutdown; marked as failed"
+                );
+            }
+        }
+        Err(e) => tracing::warn!("Could not check for interrupted jobs: {}", e),
+    }
+
+    let signing_key = match &config.download_signing_key {
+        Some(key) => key.as_bytes().to_vec(),
+        None => {
+            tracing::warn!(
+                "DOWNLOAD_SIGNING_KEY not set; download links will not survive a restart"
+            );
+            rand::thread_rng().gen::<[u8; 32]>().to_vec()
+        }
+    };
+    let local_storage = Arc::new(LocalStorage::new(
+        &config.export_dir,
+        &config.public_base_url,
+        &signing_key,
+    ));
+    let export_storage = storage::from_config(&config, local_storage.clone())
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    tracing::info!("Export storage: {}", export_storage.name());
+
+    let authenticator = Arc::new(
+        auth::Authenticator::from_config(&config)
+            .map_err(|e| std::io::Error::other(format!("Auth config error: {}", e)))?,
+    );
+    tracing::info!("Authentication: {}", authenticator.describe());
+
+    let export_limiter = Arc::new(limits::ExportLimiter::new(&config));
+    let rate_limiter = limits::RateLimiter::new(&config).map(Arc::new);
+    tracing::info!(
+        "Export limits: {} concurrent, {} per client; rate limit: {}",
+        config.export_max_concurrent,
+        config.export_max_per_client,
+        match &rate_limiter {
+            Some(_) => format!(
+                "{}/min, burst {}",
+                config.rate_limit_per_minute, config.rate_limit_burst
+            ),
+            None => "off".to_string(),
+        }
+    );
+
+    let scheduler = scheduler::Scheduler::new();
+    scheduler.start(
+        pool.clone(),
+        config.clone(),
+        export_storage.clone(),
+        export_limiter.clone(),
+        jobs.clone(),
+    );
+
+    retention::spawn_janitor(
+        config.export_dir.clone().into(),
+        config.retention.clone(),
+        config.cleanup_interval,
+    );
+
     let server_address = config.server_address();
-    println!("Starting server at http://{}", server_address);
+    tracing::info!("Starting server at http://{}", server_address);
     println!("Available endpoints:");
     println!("  POST /insert-data - Insert ~1.6M test records");
     println!("  GET  /export      - Export orders to Excel file");
-    println!("  GET  /health      - Health check");
+    println!("  GET  /reports     - List saved report definitions");
+    println!("  POST /reports/{{name}}/run - Run a saved report");
+    println!("  GET  /schedules   - Scheduled reports and run history");
+    println!("  GET  /exports     - Export job history (?status=&from=&to=&limit=&offset=)");
+    println!("  GET  /downloads/{{key}} - Download an export via a signed link");
+    println!("  GET  /health/live - Liveness probe (also /health)");
+    println!("  GET  /health/ready - Readiness probe: database, orders table, disk space");
+    println!("  GET  /metrics     - Prometheus metrics");
+    println!("All endpoints except /health/*, /metrics and /downloads require authentication");
 
-    HttpServer::new(move || {
+    let shutdown_jobs = jobs.clone();
+    let shutdown_grace = config.shutdown_grace;
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::new(config.clone()))
-            .route("/insert-data", web::post().to(insert_data_handler))
-            .route("/export", web::get().to(export_handler))
+            .app_data(web::Data::new(scheduler.clone()))
+            .app_data(web::Data::new(local_storage.clone()))
+            .app_data(web::Data::new(export_storage.clone()))
+            .app_data(web::Data::new(authenticator.clone()))
+            .app_data(web::Data::new(export_limiter.clone()))
+            .app_data(web::Data::new(rate_limiter.clone()))
+            .app_data(web::Data::new(jobs.clone()))
+            .wrap(from_fn(request_id::middleware))
+            // Outermost, so it sees the final status of every request.
+            .wrap(from_fn(metrics::middleware))
+            .default_service(web::to(not_found_handler))
+            // Public: download links carry their own signature.
+            .route("/downloads/{key}", web::get().to(download_handler))
             .route("/health", web::get().to(health_handler))
+            .route("/health/live", web::get().to(health_handler))
+            .route("/health/ready", web::get().to(readiness_handler))
+            .route("/metrics", web::get().to(metrics_handler))
+            .configure(protected_routes)
     })
+    // Signals are handled by `shutdown_gracefully`, which drains jobs first.
+    .disable_signals()
     .bind(&server_address)?
-    .run()
-    .await
+    .run();
+    tokio::spawn(shutdown_gracefully(
+        server.handle(),
+        shutdown_jobs,
+        shutdown_grace,
+    ));
+    server.await
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::Method;
+    use actix_web::test;
+
+    fn authenticator() -> Arc<auth::Authenticator> {
+        let keys =
+            std::env::temp_dir().join(format!("server-test-{}-keys.toml", std::process::id()));
+        // sha256("test")
+        std::fs::write(
+            &keys,
+            "[keys.ci]\nsha256 = \"9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08\"\n",
+        )
+        .unwrap();
+        let config = config::Config::load(&config::ConfigArgs {
+            overrides: vec![
+                "AUTH_DISABLED=false".to_string(),
+                format!("API_KEYS_FILE={}", keys.display()),
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+        let authenticator = auth::Authenticator::from_config(&config).unwrap();
+        std::fs::remove_file(&keys).unwrap();
+        Arc::new(authenticator)
+    }
+
+    #[actix_web::test]
+    async fn unknown_paths_are_not_found_without_credentials() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(authenticator()))
+                .default_service(web::to(not_found_handler))
+                .configure(protected_routes),
+        )
+        .await;
+
+        // Middleware errors become responses further out, in the real app.
+        let status = |method: Method, path: &str| {
+            let req = test::TestRequest::default()
+                .method(method)
+                .uri(path)
+                .to_request();
+            let app = &app;
+            async move {
+                match test::try_call_service(app, req).await {
+                    Ok(response) => response.status(),
+                    Err(e) => e.as_response_error().status_code(),
+                }
+            }
+        };
+        assert_eq!(status(Method::GET, "/nope").await, 404);
+        assert_eq!(status(Method::GET, "/reports/x").await, 404);
+        assert_eq!(status(Method::GET, "/reports").await, 401);
+        // a protected path says nothing about its methods before authenticating
+        assert_eq!(status(Method::POST, "/export").await, 401);
+    }
+
+    fn exports_filter(query: &str) -> Result<jobs::JobFilter, String> {
+        let query = web::Query::<ExportsQuery>::from_query(query).map_err(|e| e.to_string())?;
+        query.filter(None).map_err(|e| match e {
+            error::AppError::BadRequest(message) => message,
+            other => panic!("not a bad request: {:?}", other),
+        })
+    }
+
+    #[actix_web::test]
+    async fn exports_query_defaults() {
+        let filter = exports_filter("").unwrap();
+        assert_eq!(filter.status, None);
+        assert_eq!(filter.from, None);
+        assert_eq!(filter.to, None);
+        assert_eq!(filter.limit, jobs::DEFAULT_PAGE_SIZE);
+        assert_eq!(filter.offset, 0);
+
+        let filter =
+            exports_filter("status=failed&from=2026-10-01&to=2026-10-31&limit=500&offset=-5")
+                .unwrap();
+        assert_eq!(filter.status, Some(jobs::JobStatus::Failed));
+        assert_eq!(
+            filter.from.unwrap().to_rfc3339(),
+            "2026-10-01T00:00:00+00:00"
+        );
+        assert_eq!(filter.to.unwrap().to_rfc3339(), "2026-11-01T00:00:00+00:00");
+        assert_eq!(filter.limit, jobs::MAX_PAGE_SIZE);
+        assert_eq!(filter.offset, 0);
+    }
+
+    #[actix_web::test]
+    async fn exports_query_rejects_bad_parameters() {
+        assert!(exports_filter("status=done")
+            .unwrap_err()
+            .contains("unknown status"));
+        assert!(exports_filter("from=last-week")
+            .unwrap_err()
+            .contains("invalid time"));
+        assert!(exports_filter("to=2026-02-30")
+            .unwrap_err()
+            .contains("invalid time"));
+        for limit in ["0", "-1", "501"] {
+            assert_eq!(
+                exports_filter(&format!("limit={}", limit)).unwrap_err(),
+                "limit must be between 1 and 500"
+            );
+        }
+        // Not a number at all is rejected while deserializing.
+        assert!(exports_filter("limit=ten").is_err());
+        assert!(exports_filter("offset=ten").is_err());
+    }
+}
@@ -0,0 +1,286 @@
+//! Checkpoints of long `export_stream` runs, so a failed export resumes where
+//! it stopped instead of starting over.
+//!
+//! The checkpoint sits next to the output (`orders.xlsx.checkpoint.json`) and
+//! is rewritten every few seconds and when the export fails. A CSV export
+//! resumes by cutting its file back to the last checkpointed length and
+//! appending; an xlsx file cannot be reopened, so the export continues into a
+//! new part (`orders.part2.xlsx`).
+
+use crate::export::ExportFormat;
+use crate::query::{OrderColumn, OrderFilter};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub format: ExportFormat,
+    /// Columns as selected, so a resumed run writes the same layout.
+    pub columns: Vec<OrderColumn>,
+    pub filter: OrderFilter,
+    /// Finished part files, oldest first.
+    pub parts: Vec<Part>,
+    /// The part being written when the checkpoint was taken.
+    pub current: Part,
+    /// `current` as of the last CSV flush, when rows, id and length agreed.
+    #[serde(skip)]
+    flushed: Option<Part>,
+}
+
+/// One output file of an export.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Part {
+    pub path: PathBuf,
+    pub rows: usize,
+    /// `id` of the last row written to this part.
+    pub last_id: Option<i64>,
+    /// Length of the file when `rows` and `last_id` were recorded (CSV only).
+    pub bytes: Option<u64>,
+    /// The file was finalized and is readable.
+    pub complete: bool,
+}
+
+impl Part {
+    pub fn new(path: PathBuf) -> Self {
+        Part {
+            path,
+            rows: 0,
+            last_id: None,
+            bytes: None,
+            complete: false,
+        }
+    }
+}
+
+impl Checkpoint {
+    /// A new export whose first part is `output`.
+    pub fn new(
+        format: ExportFormat,
+        columns: Vec<OrderColumn>,
+        filter: OrderFilter,
+        output: &Path,
+    ) -> Self {
+        Checkpoint {
+            format,
+            columns,
+            filter,
+            parts: Vec::new(),
+            current: Part::new(output.to_path_buf()),
+            flushed: None,
+        }
+    }
+
+    /// Where the checkpoint of `output` is kept.
+    pub fn path_for(output: &Path) -> PathBuf {
+        let mut name = output.as_os_str().to_owned();
+        name.push(".checkpoint.json");
+        PathBuf::from(name)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        serde_json::from_str(&text).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+
+    /// Write atomically, so a crash mid-write leaves the previous checkpoint.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let json = serde_json::to_vec_pretty(self).map_err(std::io::Error::other)?;
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Rows in all parts, including the current one.
+    pub fn rows(&self) -> usize {
+        self.parts.iter().map(|p| p.rows).sum::<usize>() + self.current.rows
+    }
+
+    /// The last `id` already written; the export continues after it.
+    pub fn resume_after(&self) -> Option<i64> {
+        self.current
+            .last_id
+            .or_else(|| self.parts.iter().rev().find_map(|p| p.last_id))
+    }
+
+    /// Prepare to continue: an unfinished xlsx part is discarded and
+    /// rewritten, a finished one is kept and followed by a new part; a CSV
+    /// part is cut back to its recorded length and appended to.
+    pub fn prepare_resume(&mut self, output: &Path) -> std::io::Result<()> {
+        match self.format {
+            ExportFormat::Xlsx if self.current.complete => {
+                let finished = std::mem::replace(
+                    &mut self.current,
+                    Part::new(part_path(output, self.parts.len() + 2)),
+                );
+                self.parts.push(finished);
+            }
+            ExportFormat::Xlsx => {
+                self.current = Part::new(self.current.path.clone());
+            }
+            ExportFormat::Csv => {
+                let keep = self.current.bytes.unwrap_or(0);
+                if keep == 0 {
+                    self.current = Part::new(self.current.path.clone());
+                }
+                match std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&self.current.path)
+                {
+                    Ok(file) => file.set_len(keep)?,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound && keep == 0 => {}
+                    Err(e) => return Err(e),
+                }
+                self.flushed = Some(self.current.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// The CSV file was flushed and is `bytes` long: every row counted so
+    /// far is on disk.
+    pub fn mark_flushed(&mut self, bytes: u64) {
+        self.current.bytes = Some(bytes);
+        self.flushed = Some(self.current.clone());
+    }
+
+    /// Forget the CSV rows written since the last flush, which may not have
+    /// reached the file; a resume truncates to the length recorded with them.
+    pub fn rewind_to_flushed(&mut self) {
+        self.current = match &self.flushed {
+            Some(flushed) => flushed.clone(),
+            None => Part::new(self.current.path.clone()),
+        };
+    }
+
+    pub fn paths(&self) -> Vec<&Path> {
+        self.parts
+            .iter()
+            .chain(std::iter::once(&self.current))
+            .map(|p| p.path.as_path())
+            .collect()
+    }
+}
+
+/// `orders.xlsx` -> `orders.part2.xlsx`.
+pub fn part_path(output: &Path, part: usize) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(ext) => format!("{}.part{}.{}", stem, part, ext.to_string_lossy()),
+        None => format!("{}.part{}", stem, part),
+    };
+    output.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_output(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("checkpoint-test-{}-{}", std::process::id(), name))
+    }
+
+    fn checkpoint(format: ExportFormat, output: &Path) -> Checkpoint {
+        Checkpoint::new(format, Vec::new(), OrderFilter::default(), output)
+    }
+
+    #[test]
+    fn paths_next_to_the_output() {
+        let output = Path::new("/data/orders.xlsx");
+        assert_eq!(
+            Checkpoint::path_for(output),
+            Path::new("/data/orders.xlsx.checkpoint.json")
+        );
+        assert_eq!(part_path(output, 2), Path::new("/data/orders.part2.xlsx"));
+        assert_eq!(part_path(Path::new("orders"), 3), Path::new("orders.part3"));
+    }
+
+    #[test]
+    fn csv_resume_cuts_back_to_the_checkpointed_length() {
+        let output = temp_output("resume.csv");
+        std::fs::write(&output, "id\n1\n2\n3, partial").unwrap();
+        let mut cp = checkpoint(ExportFormat::Csv, &output);
+        cp.current.rows = 2;
+        cp.current.last_id = Some(2);
+        cp.mark_flushed(7);
+
+        let path = Checkpoint::path_for(&output);
+        cp.save(&path).unwrap();
+        let mut resumed = Checkpoint::load(&path).unwrap();
+        resumed.prepare_resume(&output).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "id\n1\n2\n");
+        assert_eq!(resumed.rows(), 2);
+        assert_eq!(resumed.resume_after(), Some(2));
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn csv_resume_without_a_flush_starts_over() {
+        let output = temp_output("unflushed.csv");
+        let mut cp = checkpoint(ExportFormat::Csv, &output);
+        cp.current.rows = 10;
+        cp.current.last_id = Some(10);
+        // the file was never created
+        cp.prepare_resume(&output).unwrap();
+        assert_eq!(cp.rows(), 0);
+        assert_eq!(cp.resume_after(), None);
+
+        std::fs::write(&output, "id\n1\n").unwrap();
+        cp.prepare_resume(&output).unwrap();
+        assert_eq!(std::fs::metadata(&output).unwrap().len(), 0);
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn rewind_forgets_rows_after_the_last_flush() {
+        let mut cp = checkpoint(ExportFormat::Csv, Path::new("orders.csv"));
+        cp.current.rows = 5;
+        cp.current.last_id = Some(50);
+        cp.rewind_to_flushed();
+        assert_eq!(cp.rows(), 0);
+        assert_eq!(cp.current.bytes, None);
+
+        cp.current.rows = 5;
+        cp.current.last_id = Some(50);
+        cp.mark_flushed(100);
+        cp.current.rows = 8;
+        cp.current.last_id = Some(80);
+        cp.rewind_to_flushed();
+        assert_eq!(cp.rows(), 5);
+        assert_eq!(cp.resume_after(), Some(50));
+        assert_eq!(cp.current.bytes, Some(100));
+    }
+
+    #[test]
+    fn xlsx_resume_starts_a_new_part_after_a_finished_one() {
+        let output = Path::new("/data/orders.xlsx");
+        let mut cp = checkpoint(ExportFormat::Xlsx, output);
+        cp.current.rows = 100;
+        cp.current.last_id = Some(100);
+        cp.current.complete = true;
+        cp.prepare_resume(output).unwrap();
+        assert_eq!(cp.parts.len(), 1);
+        assert_eq!(cp.current.path, Path::new("/data/orders.part2.xlsx"));
+        assert_eq!(cp.rows(), 100);
+        assert_eq!(cp.resume_after(), Some(100));
+
+        // an unfinished part is rewritten from its start
+        cp.current.rows = 40;
+        cp.current.last_id = Some(140);
+        cp.prepare_resume(output).unwrap();
+        assert_eq!(cp.parts.len(), 1);
+        assert_eq!(cp.current.path, Path::new("/data/orders.part2.xlsx"));
+        assert_eq!(cp.rows(), 100);
+        assert_eq!(cp.resume_after(), Some(100));
+        assert_eq!(
+            cp.paths(),
+            vec![
+                Path::new("/data/orders.xlsx"),
+                Path::new("/data/orders.part2.xlsx")
+            ]
+        );
+    }
+}
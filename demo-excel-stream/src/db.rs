@@ -1,35 +1,241 @@
 use crate::config::Config;
+use crate::metrics::metrics;
+use crate::retry::{self, RetryPolicy};
+use serde::Serialize;
 use tokio_postgres::{Client, NoTls};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::LazyLock;
+use std::time::Instant;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
+/// `application_name` of every connection, followed by [`instance_id`].
+pub const APPLICATION_NAME_PREFIX: &str = "demo-excel-stream ";
+
+/// A random UUID naming this process. Connections carry it in their
+/// `application_name`, so other servers sharing the database can tell from
+/// `pg_stat_activity` whether this process is still connected; unlike a
+/// backend pid it survives reconnects and is never reused.
+pub fn instance_id() -> &'static str {
+    static ID: LazyLock<String> = LazyLock::new(|| {
+        let mut bytes: [u8; 16] = rand::random();
+        bytes[6] = (bytes[6] & 0x0f) | 0x40; // version 4
+        bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+        let hex = hex::encode(bytes);
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    });
+    &ID
+}
+
+/// Up to `DB_POOL_SIZE` connections, opened on demand and reused. A
+/// connection that has closed (server restart, network failure) is dropped
+/// when it comes back, and the next checkout opens a new one.
 pub struct DbPool {
-    client: Arc<Mutex<Client>>,
+    idle: std::sync::Mutex<Vec<Client>>,
+    permits: Semaphore,
+    max_size: usize,
+    database_url: String,
+    retry: RetryPolicy,
 }
 
 impl DbPool {
+    /// Open the first connection, so a database that cannot be reached is
+    /// reported at startup.
     pub async fn new(config: &Config) -> Result<Self, tokio_postgres::Error> {
-        let (client, connection) = tokio_postgres::connect(&config.database_url, NoTls).await?;
-
-        // Spawn the connection task
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("Database connection error: {}", e);
-            }
-        });
+        let client = connect(&config.database_url).await?;
 
+        let metrics = metrics();
+        metrics.db_pool_max.set(config.db_pool_size as i64);
+        metrics.db_pool_connections.add(1);
         Ok(DbPool {
-            client: Arc::new(Mutex::new(client)),
+            idle: std::sync::Mutex::new(vec![client]),
+            permits: Semaphore::new(config.db_pool_size),
+            max_size: config.db_pool_size,
+            database_url: config.database_url.clone(),
+            retry: config.db_retry,
         })
     }
 
-    pub async fn get_client(&self) -> tokio::sync::MutexGuard<'_, Client> {
-        self.client.lock().await
+    /// Check out a connection: an idle one if there is one, else a new one
+    /// while the pool is below its size, else wait for one to come back.
+    pub async fn get_client(&self) -> Result<PooledClient<'_>, tokio_postgres::Error> {
+        let metrics = metrics();
+        let start = Instant::now();
+        let waiting = Waiting::new();
+        let permit = self.permits.acquire().await.expect("pool semaphore is never closed");
+        drop(waiting);
+        metrics.db_pool_wait.observe_duration(start.elapsed());
+
+        let client = match self.take_idle() {
+            Some(client) => client,
+            None => {
+                let client = connect(&self.database_url).await?;
+                metrics.db_pool_connections.add(1);
+                client
+            }
+        };
+        metrics.db_pool_in_use.add(1);
+        Ok(PooledClient {
+            client: Some(client),
+            pool: self,
+            _permit: permit,
+        })
+    }
+
+    /// The most recently returned idle connection that is still open.
+    fn take_idle(&self) -> Option<Client> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some(client) = idle.pop() {
+            if !client.is_closed() {
+                return Some(client);
+            }
+            discard_closed();
+        }
+        None
+    }
+
+    /// Pool size, open connections, how many are checked out, and tasks
+    /// waiting for one.
+    pub fn stats(&self) -> PoolStats {
+        let metrics = metrics();
+        PoolStats {
+            max_size: self.max_size,
+            connections: metrics.db_pool_connections.get(),
+            in_use: metrics.db_pool_in_use.get(),
+            waiting: metrics.db_pool_waiting.get(),
+        }
     }
 
     pub async fn execute_query(&self, query: &str) -> Result<u64, tokio_postgres::Error> {
-        let client = self.get_client().await;
+        let client = self.get_client().await?;
         client.execute(query, &[]).await
     }
+
+    /// Run `op`, retrying transient failures (see [`retry::is_transient`])
+    /// with backoff. `op` checks out its own connection, so a retry gets one
+    /// from the pool (a new one if the failed one closed) and other tasks
+    /// keep theirs meanwhile. `op` must be safe to repeat and pick up where
+    /// the failed attempt left off.
+    pub async fn with_retry<T, F, Fut>(
+        &self,
+        what: &str,
+        mut op: F,
+    ) -> Result<T, tokio_postgres::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, tokio_postgres::Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let err = match op().await {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            if attempt >= self.retry.max_attempts || !retry::is_transient(&err) {
+                return Err(err);
+            }
+            let delay = self.retry.delay(attempt);
+            metrics().db_retries.inc();
+            tracing::warn!(
+                attempt,
+                max_attempts = self.retry.max_attempts,
+                error = %retry::describe(&err),
+                "{} failed; retrying in {:?}",
+                what,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PoolStats {
+    pub max_size: usize,
+    pub connections: i64,
+    pub in_use: i64,
+    pub waiting: i64,
+}
+
+/// A checked-out connection; returned to the pool when dropped.
+pub struct PooledClient<'a> {
+    client: Option<Client>,
+    pool: &'a DbPool,
+    // Released after the connection is back in `idle`.
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("present until dropped")
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().expect("present until dropped")
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        metrics().db_pool_in_use.add(-1);
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        if client.is_closed() {
+            discard_closed();
+        } else {
+            let mut idle = self.pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+            idle.push(client);
+        }
+    }
+}
+
+/// Count a connection that closed and left the pool.
+fn discard_closed() {
+    let metrics = metrics();
+    metrics.db_pool_connections.add(-1);
+    metrics.db_reconnects.inc();
 }
 
+/// Counts a task in `db_pool_waiting`, also when its wait is cancelled.
+struct Waiting;
+
+impl Waiting {
+    fn new() -> Self {
+        metrics().db_pool_waiting.add(1);
+        Waiting
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        metrics().db_pool_waiting.add(-1);
+    }
+}
+
+async fn connect(database_url: &str) -> Result<Client, tokio_postgres::Error> {
+    let mut config: tokio_postgres::Config = database_url.parse()?;
+    config.application_name(format!("{}{}", APPLICATION_NAME_PREFIX, instance_id()));
+    let (client, connection) = config.connect(NoTls).await?;
+
+    // Spawn the connection task
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::error!(error = %e, "Database connection error");
+        }
+    });
+
+    Ok(client)
+}
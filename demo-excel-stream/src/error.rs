@@ -1,35 +1,87 @@
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
 use std::fmt;
+use std::time::Duration;
+use tokio_postgres::error::SqlState;
 
 #[derive(Debug)]
 pub enum AppError {
-    Database(String),
+    /// Keeps the driver error so its SQLSTATE can pick the HTTP status.
+    Database(tokio_postgres::Error),
     Excel(String),
+    Xlsx(rust_xlsxwriter::XlsxError),
     Config(String),
+    NotFound(String),
+    BadRequest(String),
+    /// The request clashes with current state (e.g. the report is already running).
+    Conflict(String),
+    Unauthorized(String),
+    Forbidden(String),
+    /// Client over its limits; retry after the given delay.
+    TooManyRequests(String, Duration),
+    /// Server at capacity; retry after the given delay.
+    Unavailable(String, Duration),
+    /// The work did not finish in time.
+    Timeout(String),
+    /// The work was stopped before it finished.
+    Cancelled(String),
+    Storage(String),
+    /// A query result the export cannot represent, e.g. a column type.
+    Unsupported(String),
     Io(std::io::Error),
 }
 
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            AppError::Database(msg) => write!(f, "Database error: {}", msg),
+            AppError::Database(err) => match err.as_db_error() {
+                Some(db) => write!(f, "Database error: {} ({})", db.message(), db.code().code()),
+                // The driver keeps the cause (e.g. connection refused) out of
+                // its own message.
+                None => match std::error::Error::source(err) {
+                    Some(cause) => write!(f, "Database error: {}: {}", err, cause),
+                    None => write!(f, "Database error: {}", err),
+                },
+            },
             AppError::Excel(msg) => write!(f, "Excel error: {}", msg),
+            AppError::Xlsx(err) => write!(f, "Excel error: {}", err),
             AppError::Config(msg) => write!(f, "Config error: {}", msg),
+            AppError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            AppError::BadRequest(msg) => write!(f, "Bad request: {}", msg),
+            AppError::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            AppError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            AppError::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
+            AppError::TooManyRequests(msg, _) => write!(f, "Too many requests: {}", msg),
+            AppError::Unavailable(msg, _) => write!(f, "Service unavailable: {}", msg),
+            AppError::Timeout(msg) => write!(f, "Timed out: {}", msg),
+            AppError::Cancelled(msg) => write!(f, "Cancelled: {}", msg),
+            AppError::Storage(msg) => write!(f, "Storage error: {}", msg),
+            AppError::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
             AppError::Io(err) => write!(f, "IO error: {}", err),
         }
     }
 }
 
-impl std::error::Error for AppError {}
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AppError::Database(err) => Some(err),
+            AppError::Xlsx(err) => Some(err),
+            AppError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl From<tokio_postgres::Error> for AppError {
     fn from(err: tokio_postgres::Error) -> Self {
-        AppError::Database(err.to_string())
+        AppError::Database(err)
     }
 }
 
 impl From<rust_xlsxwriter::XlsxError> for AppError {
     fn from(err: rust_xlsxwriter::XlsxError) -> Self {
-        AppError::Excel(err.to_string())
+        AppError::Xlsx(err)
     }
 }
 
@@ -39,11 +91,150 @@ impl From<std::io::Error> for AppError {
     }
 }
 
-impl actix_web::error::ResponseError for AppError {
-    fn error_response(&self) -> actix_web::HttpResponse {
-        actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": self.to_string()
-        }))
+/// Status used for [`AppError::Cancelled`] (nginx's "client closed request").
+const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+impl AppError {
+    /// HTTP status and the stable, machine-readable `code` of the error body.
+    pub fn classify(&self) -> (StatusCode, &'static str) {
+        match self {
+            AppError::Database(err) => classify_database(err),
+            AppError::Excel(_) | AppError::Xlsx(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "excel_error")
+            }
+            AppError::Config(_) => (StatusCode::INTERNAL_SERVER_ERROR, "config_error"),
+            AppError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+            AppError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request"),
+            AppError::Conflict(_) => (StatusCode::CONFLICT, "conflict"),
+            AppError::Unauthorized(_) => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            AppError::Forbidden(_) => (StatusCode::FORBIDDEN, "forbidden"),
+            AppError::TooManyRequests(..) => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            AppError::Unavailable(..) => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+            AppError::Timeout(_) => (StatusCode::GATEWAY_TIMEOUT, "timeout"),
+            AppError::Cancelled(_) => (
+                StatusCode::from_u16(CLIENT_CLOSED_REQUEST).expect("valid status"),
+                "cancelled",
+            ),
+            AppError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, "storage_error"),
+            AppError::Unsupported(_) => (StatusCode::INTERNAL_SERVER_ERROR, "unsupported"),
+            AppError::Io(_) => (StatusCode::INTERNAL_SERVER_ERROR, "io_error"),
+        }
+    }
+
+    /// The message shown to callers. Details of server-side failures stay
+    /// in the log, where the request id finds them.
+    fn public_message(&self) -> String {
+        let (status, _) = self.classify();
+        match self {
+            AppError::Database(err) if !status.is_server_error() => err
+                .as_db_error()
+                .map(|db| db.message().to_string())
+                .unwrap_or_else(|| self.to_string()),
+            AppError::Database(_) if status == StatusCode::SERVICE_UNAVAILABLE => {
+                "database unavailable".to_string()
+            }
+            AppError::Database(_) if status == StatusCode::GATEWAY_TIMEOUT => {
+                "database query timed out".to_string()
+            }
+            // Names a column and how to fix the query; nothing internal.
+            AppError::Unsupported(_) => self.to_string(),
+            _ if status == StatusCode::INTERNAL_SERVER_ERROR => "internal server error".to_string(),
+            _ => self.to_string(),
+        }
+    }
+
+    /// The JSON error response, tagged with `request_id` when known:
+    ///
+    /// ```json
+    /// {"error": "Not found: report 'x'", "code": "not_found", "request_id": "…"}
+    /// ```
+    pub fn to_response(&self, request_id: Option<&str>) -> HttpResponse {
+        let (status, code) = self.classify();
+        let mut response = HttpResponse::build(status);
+        match self {
+            AppError::Unauthorized(_) => {
+                response.insert_header(("WWW-Authenticate", "Bearer"));
+            }
+            AppError::TooManyRequests(_, retry_after) | AppError::Unavailable(_, retry_after) => {
+                // Whole seconds, rounded up.
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response.insert_header(("Retry-After", secs.max(1).to_string()));
+            }
+            _ => {}
+        }
+        response.json(error_body(&self.public_message(), code, request_id))
+    }
+}
+
+/// Body shared by [`AppError`] and the request-id middleware's rewrite of
+/// other errors.
+pub fn error_body(message: &str, code: &str, request_id: Option<&str>) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "error": message,
+        "code": code,
+    });
+    if let Some(id) = request_id {
+        body["request_id"] = serde_json::Value::from(id);
     }
+    body
 }
 
+/// Maps a Postgres failure to an HTTP status by SQLSTATE class: bad input
+/// and constraint violations are the caller's, lost connections and
+/// overload are temporary, everything else is ours.
+fn classify_database(err: &tokio_postgres::Error) -> (StatusCode, &'static str) {
+    let Some(state) = err.code() else {
+        // No SQLSTATE: the connection failed or closed, or a value did not
+        // convert.
+        return if err.is_closed() || source_is_io(err) {
+            (StatusCode::SERVICE_UNAVAILABLE, "database_unavailable")
+        } else {
+            (StatusCode::INTERNAL_SERVER_ERROR, "database_error")
+        };
+    };
+    if *state == SqlState::UNIQUE_VIOLATION || *state == SqlState::EXCLUSION_VIOLATION {
+        return (StatusCode::CONFLICT, "conflict");
+    }
+    if *state == SqlState::QUERY_CANCELED
+        || *state == SqlState::IDLE_IN_TRANSACTION_SESSION_TIMEOUT
+    {
+        // Also what `statement_timeout` raises.
+        return (StatusCode::GATEWAY_TIMEOUT, "timeout");
+    }
+    if *state == SqlState::ADMIN_SHUTDOWN
+        || *state == SqlState::CRASH_SHUTDOWN
+        || *state == SqlState::CANNOT_CONNECT_NOW
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, "database_unavailable");
+    }
+    match &state.code()[..2] {
+        // Data exceptions and integrity constraints.
+        "22" | "23" => (StatusCode::BAD_REQUEST, "bad_request"),
+        // Serialization failures and deadlocks; safe to retry.
+        "40" => (StatusCode::SERVICE_UNAVAILABLE, "database_busy"),
+        // Connection exceptions and insufficient resources.
+        "08" | "53" => (StatusCode::SERVICE_UNAVAILABLE, "database_unavailable"),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "database_error"),
+    }
+}
+
+pub(crate) fn source_is_io(err: &tokio_postgres::Error) -> bool {
+    let mut source = std::error::Error::source(err);
+    while let Some(inner) = source {
+        if inner.is::<std::io::Error>() {
+            return true;
+        }
+        source = inner.source();
+    }
+    false
+}
+
+impl actix_web::error::ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        self.classify().0
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.to_response(None)
+    }
+}
@@ -1,55 +1,306 @@
 use crate::db::DbPool;
 use crate::error::AppError;
 use crate::config::Config;
-use rust_xlsxwriter::{Workbook, Worksheet, XlsxError};
-use std::path::PathBuf;
+use crate::metrics::metrics;
+use crate::output;
+use crate::protection;
+use crate::query::{column_header, ExportQuery, PagePosition};
+use crate::redaction::{Redaction, RedactionPolicy, Redactor};
+use crate::row::{map_row_redacted, CellData};
+use crate::style::{self, ColumnWidths, StyleOptions};
+use crate::summary::{self, Dimension, SummaryBuilder, SummaryTable};
+use crate::template::{TemplateSpec, TemplateWriter};
+use crate::timeouts::Deadline;
+use rust_xlsxwriter::{Workbook, Worksheet};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use rust_decimal::prelude::ToPrimitive;
+use std::time::Instant;
+use tracing::Instrument;
+
+/// Output file format of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Xlsx,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Xlsx => "xlsx",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+/// What to export and how to lay it out.
+pub struct ExportSpec {
+    pub query: ExportQuery,
+    pub sheet_name: String,
+    pub format: ExportFormat,
+    /// Ignored for CSV.
+    pub style: StyleOptions,
+    /// Append summary sheets after the detail sheet. Ignored for CSV.
+    pub summary: bool,
+    /// Add charts to the summary sheets; implies `summary`. Ignored for CSV.
+    pub charts: bool,
+    /// Fill this template instead of a blank workbook. The template supplies
+    /// all formatting, so `sheet_name`, `style`, `summary` and `charts` are
+    /// ignored. Ignored for CSV.
+    pub template: Option<TemplateSpec>,
+    /// Encrypt the finished workbook with this password. Not supported for
+    /// CSV.
+    pub password: Option<String>,
+    /// Protect the sheets against edits, with this password unless empty.
+    /// For templates only the data sheet is protected. Ignored for CSV.
+    pub sheet_password: Option<String>,
+    /// Role of the caller, selecting its redaction policy from
+    /// `config.redaction`.
+    pub role: Option<String>,
+    /// Extra redaction for this export. It can only tighten the role's policy.
+    pub redact: RedactionPolicy,
+}
+
+impl Default for ExportSpec {
+    fn default() -> Self {
+        ExportSpec {
+            query: ExportQuery::default(),
+            sheet_name: "Orders".to_string(),
+            format: ExportFormat::Xlsx,
+            style: StyleOptions::default(),
+            summary: false,
+            charts: false,
+            template: None,
+            password: None,
+            sheet_password: None,
+            role: None,
+            redact: RedactionPolicy::default(),
+        }
+    }
+}
+
+impl ExportSpec {
+    /// Whether summary sheets are added after the detail sheet.
+    fn wants_summary(&self) -> bool {
+        (self.summary || self.charts)
+            && self.format == ExportFormat::Xlsx
+            && self.template.is_none()
+    }
+}
+
+/// A finished export.
+#[derive(Debug, Clone)]
+pub struct ExportedFile {
+    pub path: PathBuf,
+    /// Data rows written, not counting the header.
+    pub rows: u64,
+}
 
 pub async fn export_to_excel(
     pool: Arc<DbPool>,
     config: &Config,
+    style: StyleOptions,
     output_path: Option<PathBuf>,
 ) -> Result<PathBuf, AppError> {
+    let spec = ExportSpec {
+        style,
+        ..ExportSpec::default()
+    };
+    Ok(export_query(pool, config, &spec, output_path).await?.path)
+}
 
-    // Create workbook - write directly to file for memory efficiency
-    let file_path = output_path.unwrap_or_else(|| {
-        // Save into project root (current working directory)
-        std::env::current_dir()
-            .unwrap_or_else(|_| std::env::temp_dir())
-            .join(format!("orders_export_{}.xlsx", chrono::Utc::now().timestamp()))
-    });
+/// Run `spec` and write the result to `output_path`, or to a new uniquely
+/// named `orders_export_*` file in `config.export_dir` when `None`. A partially
+/// written file is removed if the export fails, including when it runs past
+/// `config.export_deadline` ([`AppError::Timeout`]), and if the future is
+/// dropped before it completes (a cancelled job).
+pub async fn export_query(
+    pool: Arc<DbPool>,
+    config: &Config,
+    spec: &ExportSpec,
+    output_path: Option<PathBuf>,
+) -> Result<ExportedFile, AppError> {
+    if spec.password.is_some() && spec.format == ExportFormat::Csv {
+        return Err(AppError::BadRequest(
+            "CSV exports cannot be password-protected".to_string(),
+        ));
+    }
+    // Found before any rows are streamed, not when the summary is added.
+    if spec.wants_summary() && Dimension::is_summary_sheet_name(&spec.sheet_name) {
+        return Err(AppError::BadRequest(format!(
+            "sheet name '{}' is taken by a summary sheet",
+            spec.sheet_name
+        )));
+    }
 
-    let mut workbook = Workbook::new();
-    let mut worksheet = workbook.add_worksheet();
-    worksheet.set_name("Orders")?;
+    let (file_path, _reserved) = match output_path {
+        Some(path) => (path, None),
+        None => {
+            let reserved = output::reserve(
+                Path::new(&config.export_dir),
+                "orders_export",
+                spec.format.extension(),
+            )?;
+            (reserved.path().to_path_buf(), Some(reserved))
+        }
+    };
+
+    let start = Instant::now();
+    let format = spec.format.extension();
+    let metrics = metrics();
+    let span = tracing::info_span!(
+        "export",
+        export_id = %format!("{:016x}", rand::random::<u64>()),
+        format,
+        rows = tracing::field::Empty,
+        bytes = tracing::field::Empty,
+    );
+    let mut unfinished = Unfinished {
+        path: file_path.clone(),
+        format,
+        span: span.clone(),
+        done: false,
+    };
+    let result = write_export(pool, config, spec, &file_path)
+        .instrument(span.clone())
+        .await;
+    unfinished.done = true;
+    let _entered = span.enter();
+    match result {
+        Ok(rows) => {
+            span.record("rows", rows);
+            metrics.exports.with(&[format, "success"]).inc();
+            metrics.export_rows.with(&[format]).inc_by(rows);
+            if let Ok(meta) = std::fs::metadata(&file_path) {
+                metrics.export_bytes.with(&[format]).inc_by(meta.len());
+                span.record("bytes", meta.len());
+            }
+            metrics
+                .export_duration
+                .with(&[format])
+                .observe_duration(start.elapsed());
+            tracing::info!(
+                rows,
+                elapsed_ms = start.elapsed().as_millis() as u64,
+                "export completed"
+            );
+            Ok(ExportedFile {
+                path: file_path,
+                rows,
+            })
+        }
+        Err(e) => {
+            let outcome = match e {
+                AppError::Timeout(_) => "timeout",
+                _ => "error",
+            };
+            metrics.exports.with(&[format, outcome]).inc();
+            tracing::error!(outcome, error = %e, "export failed");
+            let _ = std::fs::remove_file(&file_path);
+            Err(e)
+        }
+    }
+}
+
+/// Removes the file of an export that is dropped while writing it, e.g. a
+/// job cancelled at shutdown.
+struct Unfinished {
+    path: PathBuf,
+    format: &'static str,
+    span: tracing::Span,
+    done: bool,
+}
+
+impl Drop for Unfinished {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let _entered = self.span.enter();
+        metrics().exports.with(&[self.format, "cancelled"]).inc();
+        let _ = std::fs::remove_file(&self.path);
+        tracing::warn!("export cancelled; partial file removed");
+    }
+}
+
+async fn write_export(
+    pool: Arc<DbPool>,
+    config: &Config,
+    spec: &ExportSpec,
+    file_path: &Path,
+) -> Result<u64, AppError> {
+    let deadline = Deadline::after(config.export_deadline);
+    let timeouts = config.db_timeouts;
+
+    // Prepare once to learn the result columns, so the header is written even
+    // when the query returns no rows.
+    let columns: Vec<String> = deadline
+        .run(pool.with_retry("Preparing export query", || async {
+            let client = pool.get_client().await?;
+            let stmt = client.prepare(spec.query.sql()).await?;
+            Ok(stmt
+                .columns()
+                .iter()
+                .map(|c| c.name().to_string())
+                .collect())
+        }))
+        .await??;
+    let mut policy = config
+        .redaction
+        .policy_for(spec.role.as_deref())
+        .tighten(&spec.redact);
+    if let Some(column) = spec.query.hidden_column() {
+        policy.columns.insert(column.to_string(), Redaction::Drop);
+    }
+    let redactor = Redactor::new(&columns, &policy, config.redaction_hash_key.as_deref())?;
+    let columns = redactor.kept(&columns);
+
+    let mut writer = SheetWriter::new(spec, file_path)?;
+    let mut summary = spec
+        .wants_summary()
+        .then(|| SummaryBuilder::new(&columns))
+        .filter(|s| !s.is_empty());
 
     // Write headers
-    write_headers(&mut worksheet)?;
+    writer.write_header(&columns)?;
 
     // Use batch fetching to minimize memory usage
     let batch_size = config.batch_size;
-    let mut offset = 0;
+    let mut position = PagePosition::default();
     let mut row_index = 1u32; // Start after header row
 
     loop {
-        // Fetch batch of rows
-        let rows = {
-            let client = pool.get_client().await;
-            client
+        deadline.check()?;
+
+        // Fetch batch of rows; a failed fetch is retried from the same position.
+        // Each batch is its own transaction carrying the export's time limits;
+        // waiting for the connection counts against the deadline too.
+        let (db, at) = (&pool, &position);
+        let fetch = pool.with_retry("Fetching export batch", || async move {
+            let mut client = db.get_client().await?;
+            let tx = client.transaction().await?;
+            let limits = timeouts.set_local_sql(deadline.remaining());
+            if !limits.is_empty() {
+                tx.batch_execute(&limits).await?;
+            }
+            let rows = tx
                 .query(
-                    &format!(
-                        "SELECT id, order_number, customer_id, customer_name, customer_email, 
-                         order_date, status, total_amount, shipping_address, city, state, 
-                         country, postal_code, payment_method, payment_status, shipping_method, 
-                         tracking_number, notes, created_at 
-                         FROM orders ORDER BY id LIMIT {} OFFSET {}",
-                        batch_size, offset
-                    ),
-                    &[],
+                    &spec.query.page_sql(batch_size, at),
+                    &spec.query.page_params(at),
                 )
-                .await?
-        };
+                .await?;
+            tx.commit().await?;
+            Ok(rows)
+        });
+        let rows = deadline
+            .run(fetch)
+            .await?
+            .map_err(|e| timeouts.classify(e, &deadline))?;
 
         if rows.is_empty() {
             break;
@@ -57,17 +308,20 @@ pub async fn export_to_excel(
 
         let rows_len = rows.len();
 
-        // Write each row immediately to Excel (streaming approach)
+        // Write each row immediately to the output (streaming approach)
         for row in rows {
-            write_order_row(&mut worksheet, row_index, &row)?;
+            spec.query.advance(&mut position, &row);
+            let cells = map_row_redacted(&row, &redactor)?;
+            if let Some(summary) = summary.as_mut() {
+                summary.observe_cells(&cells);
+            }
+            writer.write_row(row_index, &cells)?;
             row_index += 1;
         }
 
-        offset += batch_size;
-
         // Log progress every 10k rows
-        if offset % 10000 == 0 {
-            println!("Exported {} rows...", offset);
+        if position.rows % 10000 == 0 {
+            tracing::debug!(rows = position.rows, "export progress");
         }
 
         // If we got fewer rows than batch_size, we're done
@@ -76,92 +330,200 @@ pub async fn export_to_excel(
         }
     }
 
-    // Save workbook to file
-    workbook.save(&file_path)?;
+    // Save (and encrypt) on a blocking thread, within the deadline. That
+    // work cannot be stopped once started, so when the export gives up on it
+    // the task removes the file itself on completion.
+    deadline.check()?;
+    let rows = row_index - 1;
+    let tables = summary.map(SummaryBuilder::finish).unwrap_or_default();
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let finish = {
+        let (abandoned, path) = (abandoned.clone(), file_path.to_path_buf());
+        let (password, charts) = (spec.password.clone(), spec.charts);
+        tokio::task::spawn_blocking(move || {
+            let result = writer
+                .finish(&columns, rows, &tables, charts, &path)
+                .and_then(|()| match &password {
+                    // 100 000 rounds of SHA-512 plus a pass over the file
+                    Some(password) => protection::encrypt_file(&path, password),
+                    None => Ok(()),
+                });
+            if abandoned.load(Ordering::SeqCst) {
+                let _ = std::fs::remove_file(&path);
+            }
+            result
+        })
+    };
+    let _abandon = SetOnDrop(abandoned);
+    deadline
+        .run(finish)
+        .await?
+        .map_err(|e| AppError::Excel(format!("saving the export failed: {}", e)))??;
 
-    println!("Export completed. Total rows: {}", row_index - 1);
-    Ok(file_path)
+    Ok(u64::from(rows))
 }
 
-fn write_headers(worksheet: &mut Worksheet) -> Result<(), XlsxError> {
-    let headers = [
-        "ID",
-        "Order Number",
-        "Customer ID",
-        "Customer Name",
-        "Customer Email",
-        "Order Date",
-        "Status",
-        "Total Amount",
-        "Shipping Address",
-        "City",
-        "State",
-        "Country",
-        "Postal Code",
-        "Payment Method",
-        "Payment Status",
-        "Shipping Method",
-        "Tracking Number",
-        "Notes",
-        "Created At",
-    ];
-
-    for (col, header) in headers.iter().enumerate() {
-        worksheet.write_string(0, col as u16, *header)?;
+/// Sets the flag when dropped, i.e. when the export returns or is cancelled.
+struct SetOnDrop(Arc<AtomicBool>);
+
+impl Drop for SetOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
     }
+}
 
-    Ok(())
+/// Destination for exported rows, one variant per output format.
+enum SheetWriter {
+    Xlsx {
+        workbook: Box<Workbook>,
+        worksheet: Box<Worksheet>,
+        style: StyleOptions,
+        widths: ColumnWidths,
+        sheet_password: Option<String>,
+    },
+    Template(Box<TemplateWriter>),
+    Csv(BufWriter<File>),
 }
 
-fn write_order_row(
-    worksheet: &mut Worksheet,
-    row: u32,
-    db_row: &tokio_postgres::Row,
-) -> Result<(), AppError> {
-    let id: i32 = db_row.get(0);
-    let order_number: String = db_row.get(1);
-    let customer_id: i32 = db_row.get(2);
-    let customer_name: String = db_row.get(3);
-    let customer_email: String = db_row.get(4);
-    let order_date: chrono::NaiveDate = db_row.get(5);
-    let status: String = db_row.get(6);
-    let total_amount: rust_decimal::Decimal = db_row.get(7);
-    let shipping_address: String = db_row.get(8);
-    let city: String = db_row.get(9);
-    let state: String = db_row.get(10);
-    let country: String = db_row.get(11);
-    let postal_code: String = db_row.get(12);
-    let payment_method: String = db_row.get(13);
-    let payment_status: String = db_row.get(14);
-    let shipping_method: String = db_row.get(15);
-    let tracking_number: Option<String> = db_row.get(16);
-    let notes: Option<String> = db_row.get(17);
-    let created_at: chrono::NaiveDateTime = db_row.get(18);
-
-    worksheet.write_number(row, 0, id as f64)?;
-    worksheet.write_string(row, 1, &order_number)?;
-    worksheet.write_number(row, 2, customer_id as f64)?;
-    worksheet.write_string(row, 3, &customer_name)?;
-    worksheet.write_string(row, 4, &customer_email)?;
-    worksheet.write_string(row, 5, &order_date.to_string())?;
-    worksheet.write_string(row, 6, &status)?;
-    if let Some(v) = total_amount.to_f64() {
-        worksheet.write_number(row, 7, v)?;
-    } else {
-        worksheet.write_string(row, 7, &total_amount.to_string())?;
+impl SheetWriter {
+    fn new(spec: &ExportSpec, path: &Path) -> Result<Self, AppError> {
+        match spec.format {
+            ExportFormat::Xlsx if spec.template.is_some() => {
+                let template = spec.template.as_ref().expect("checked above");
+                let mut writer = TemplateWriter::create(template, path)?;
+                if let Some(password) = &spec.sheet_password {
+                    writer.protect(password);
+                }
+                Ok(SheetWriter::Template(Box::new(writer)))
+            }
+            ExportFormat::Xlsx => {
+                let mut worksheet = Worksheet::new();
+                worksheet.set_name(&spec.sheet_name)?;
+                Ok(SheetWriter::Xlsx {
+                    workbook: Box::new(Workbook::new()),
+                    worksheet: Box::new(worksheet),
+                    style: spec.style,
+                    widths: ColumnWidths::new::<&str>(&[]),
+                    sheet_password: spec.sheet_password.clone(),
+                })
+            }
+            ExportFormat::Csv => Ok(SheetWriter::Csv(BufWriter::new(File::create(path)?))),
+        }
+    }
+
+    fn write_header(&mut self, columns: &[String]) -> Result<(), AppError> {
+        let headers: Vec<String> = columns.iter().map(|c| column_header(c)).collect();
+        match self {
+            SheetWriter::Xlsx {
+                worksheet,
+                style,
+                widths,
+                ..
+            } => {
+                *widths = ColumnWidths::new(&headers);
+                let format = style::xlsx_header_format(style);
+                for (col, header) in headers.iter().enumerate() {
+                    match &format {
+                        Some(format) => {
+                            worksheet.write_string_with_format(0, col as u16, header, format)?;
+                        }
+                        None => {
+                            worksheet.write_string(0, col as u16, header)?;
+                        }
+                    }
+                }
+            }
+            SheetWriter::Template(writer) => writer.write_header(&headers)?,
+            SheetWriter::Csv(out) => write_csv_record(out, headers.iter().map(|h| h.as_str()))?,
+        }
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: u32, cells: &[CellData]) -> Result<(), AppError> {
+        match self {
+            SheetWriter::Xlsx {
+                worksheet,
+                style,
+                widths,
+                ..
+            } => {
+                if style.auto_width && widths.sampling() {
+                    widths.observe(cells.iter().map(CellData::display_len));
+                }
+                for (col, cell) in cells.iter().enumerate() {
+                    let col = col as u16;
+                    match cell {
+                        CellData::Empty => {}
+                        CellData::Int(v) => {
+                            worksheet.write_number(row, col, *v as f64)?;
+                        }
+                        CellData::Number(v) => {
+                            worksheet.write_number(row, col, *v)?;
+                        }
+                        CellData::Text(s) => {
+                            worksheet.write_string(row, col, s)?;
+                        }
+                    }
+                }
+            }
+            SheetWriter::Template(writer) => writer.write_row(cells)?,
+            SheetWriter::Csv(out) => {
+                let texts: Vec<String> = cells.iter().map(|c| c.to_text()).collect();
+                write_csv_record(out, texts.iter().map(|t| t.as_str()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(
+        self,
+        columns: &[String],
+        rows: u32,
+        summary: &[SummaryTable],
+        charts: bool,
+        path: &Path,
+    ) -> Result<(), AppError> {
+        match self {
+            SheetWriter::Xlsx {
+                mut workbook,
+                mut worksheet,
+                style,
+                widths,
+                sheet_password,
+            } => {
+                style::add_xlsx_conditional_formats(&mut worksheet, &style, columns, rows)?;
+                style::apply_xlsx_layout(&mut worksheet, &style, &widths, rows)?;
+                workbook.push_worksheet(*worksheet);
+                let header = style::xlsx_header_format(&style);
+                summary::write_xlsx(&mut workbook, summary, header.as_ref(), charts)?;
+                if let Some(password) = &sheet_password {
+                    for worksheet in workbook.worksheets_mut() {
+                        protection::protect_xlsx_sheet(worksheet, password);
+                    }
+                }
+                workbook.save(path)?;
+            }
+            SheetWriter::Template(writer) => writer.finish()?,
+            SheetWriter::Csv(mut out) => out.flush()?,
+        }
+        Ok(())
     }
-    worksheet.write_string(row, 8, &shipping_address)?;
-    worksheet.write_string(row, 9, &city)?;
-    worksheet.write_string(row, 10, &state)?;
-    worksheet.write_string(row, 11, &country)?;
-    worksheet.write_string(row, 12, &postal_code)?;
-    worksheet.write_string(row, 13, &payment_method)?;
-    worksheet.write_string(row, 14, &payment_status)?;
-    worksheet.write_string(row, 15, &shipping_method)?;
-    worksheet.write_string(row, 16, &tracking_number.unwrap_or_default())?;
-    worksheet.write_string(row, 17, &notes.unwrap_or_default())?;
-    worksheet.write_string(row, 18, &created_at.format("%Y-%m-%d %H:%M:%S").to_string())?;
-
-    Ok(())
 }
 
+/// Write one CSV record (RFC 4180 quoting, CRLF line ends).
+pub fn write_csv_record<'a>(
+    out: &mut impl Write,
+    fields: impl Iterator<Item = &'a str>,
+) -> std::io::Result<()> {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            write!(out, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            out.write_all(field.as_bytes())?;
+        }
+    }
+    out.write_all(b"\r\n")
+}
@@ -0,0 +1,221 @@
+//! Liveness and readiness probes.
+//!
+//! Liveness only says the process is up and serving requests. Readiness
+//! checks what an export depends on: a working database connection, the
+//! `orders` table, and free space in `EXPORT_DIR`. It reports pool and job
+//! counts alongside, so an orchestrator or load balancer can stop sending
+//! work to an instance that could not finish it.
+
+use crate::config::Config;
+use crate::db::{DbPool, PoolStats};
+use crate::jobs::Jobs;
+use crate::limits::ExportLimiter;
+use crate::scheduler::Scheduler;
+use serde::Serialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Result of [`readiness`]; `ready` decides between `200` and `503`.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    #[serde(skip)]
+    pub ready: bool,
+    /// `ok`, `unavailable`, or `shutting_down` once the server has stopped
+    /// taking new jobs.
+    pub status: &'static str,
+    pub checks: Checks,
+    pub pool: PoolStats,
+    pub jobs: JobStats,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Checks {
+    pub database: Check,
+    pub orders_table: Check,
+    pub export_dir: DiskCheck,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskCheck {
+    pub status: CheckStatus,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_free_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    /// Not checked because an earlier check did not get through.
+    Skipped,
+    Error,
+}
+
+impl CheckStatus {
+    fn is_healthy(self) -> bool {
+        self != CheckStatus::Error
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobStats {
+    /// Exports, seeding runs and report runs holding an export slot.
+    pub exports_running: usize,
+    pub export_slots: usize,
+    pub scheduled_reports_running: usize,
+}
+
+impl Check {
+    fn new(status: CheckStatus) -> Self {
+        Check {
+            status,
+            latency_ms: None,
+            error: None,
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Check {
+            error: Some(message.into()),
+            ..Check::new(CheckStatus::Error)
+        }
+    }
+}
+
+/// Run every readiness check, each within `config.health_check_timeout`.
+/// A server that is shutting down is never ready.
+pub async fn readiness(
+    pool: &DbPool,
+    config: &Config,
+    limiter: &ExportLimiter,
+    scheduler: &Scheduler,
+    jobs: &Jobs,
+) -> Readiness {
+    let (database, orders_table) = check_database(pool, config.health_check_timeout).await;
+    let export_dir = check_export_dir(Path::new(&config.export_dir), config.health_min_free_disk);
+
+    let healthy = database.status.is_healthy()
+        && orders_table.status.is_healthy()
+        && export_dir.status.is_healthy();
+    let ready = healthy && !jobs.is_draining();
+    Readiness {
+        ready,
+        status: match (healthy, ready) {
+            (_, true) => "ok",
+            (true, false) => "shutting_down",
+            (false, false) => "unavailable",
+        },
+        checks: Checks {
+            database,
+            orders_table,
+            export_dir,
+        },
+        pool: pool.stats(),
+        jobs: JobStats {
+            exports_running: limiter.running(),
+            export_slots: limiter.capacity(),
+            scheduled_reports_running: scheduler.running_count(),
+        },
+    }
+}
+
+/// Ping the database and look for the `orders` table in one query, on a
+/// connection from the pool. A pool that cannot hand one out in time is not
+/// ready: exports would wait just the same.
+async fn check_database(pool: &DbPool, limit: Duration) -> (Check, Check) {
+    let start = Instant::now();
+    let query = async {
+        let client = pool.get_client().await?;
+        probe(&client).await
+    };
+    match tokio::time::timeout(limit, query).await {
+        Ok(result) => checks_from(result, start),
+        Err(_) => (
+            Check::error(format!(
+                "no pool connection or answer within {}s",
+                limit.as_secs_f64()
+            )),
+            Check::new(CheckStatus::Skipped),
+        ),
+    }
+}
+
+/// Whether the `orders` table exists, which also proves the connection works.
+async fn probe(client: &tokio_postgres::Client) -> Result<bool, tokio_postgres::Error> {
+    Ok(client
+        .query_one("SELECT to_regclass('orders') IS NOT NULL", &[])
+        .await?
+        .get(0))
+}
+
+fn checks_from(result: Result<bool, tokio_postgres::Error>, start: Instant) -> (Check, Check) {
+    match result {
+        Ok(orders_exist) => {
+            let database = Check {
+                latency_ms: Some(start.elapsed().as_millis() as u64),
+                ..Check::new(CheckStatus::Ok)
+            };
+            let orders_table = if orders_exist {
+                Check::new(CheckStatus::Ok)
+            } else {
+                Check::error("table orders does not exist; run `server migrate`")
+            };
+            (database, orders_table)
+        }
+        Err(e) => (
+            Check::error(crate::retry::describe(&e)),
+            Check::new(CheckStatus::Skipped),
+        ),
+    }
+}
+
+/// Free space on the file system holding `dir` (or the closest existing
+/// parent, since the directory is created on first export).
+fn check_export_dir(dir: &Path, min_free: Option<u64>) -> DiskCheck {
+    let mut check = DiskCheck {
+        status: CheckStatus::Ok,
+        path: dir.display().to_string(),
+        free_bytes: None,
+        min_free_bytes: min_free,
+        error: None,
+    };
+    let existing = dir
+        .ancestors()
+        .map(|p| {
+            if p.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                p
+            }
+        })
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("."));
+    match fs4::available_space(existing) {
+        Ok(free) => {
+            check.free_bytes = Some(free);
+            if min_free.is_some_and(|min| free < min) {
+                check.status = CheckStatus::Error;
+                check.error = Some("free disk space is below HEALTH_MIN_FREE_DISK_MB".to_string());
+            }
+        }
+        Err(e) => {
+            check.status = CheckStatus::Error;
+            check.error = Some(e.to_string());
+        }
+    }
+    check
+}
@@ -1,105 +1,136 @@
 use crate::db::DbPool;
 use crate::error::AppError;
+use crate::metrics::metrics;
 use chrono::{NaiveDate, Utc};
 use rand::Rng;
 use rust_decimal::Decimal;
 use rust_decimal::prelude::FromPrimitive;
 use std::sync::Arc;
+use std::time::Instant;
+use tracing::Instrument;
+
+/// Insert random orders up to `total_rows`, returning how many were new.
+pub async fn insert_test_data(pool: Arc<DbPool>, total_rows: usize) -> Result<u64, AppError> {
+    insert_rows(pool, total_rows)
+        .instrument(tracing::info_span!("seed", total_rows))
+        .await
+}
 
-pub async fn insert_test_data(pool: Arc<DbPool>, total_rows: usize) -> Result<(), AppError> {
+async fn insert_rows(pool: Arc<DbPool>, total_rows: usize) -> Result<u64, AppError> {
     let batch_size = 1000;
     let mut inserted = 0;
 
-    println!("Starting to insert {} rows...", total_rows);
+    tracing::info!("Starting to insert {} rows", total_rows);
+    let metrics = metrics();
+    let start = Instant::now();
 
     for batch_start in (1500000..total_rows).step_by(batch_size) {
         let batch_end = (batch_start + batch_size).min(total_rows);
         
-        // Prepare statement per batch to ensure it's on the same connection
-        let client = pool.get_client().await;
-        let stmt = client
-            .prepare(
-                "INSERT INTO orders (
-                    order_number, customer_id, customer_name, customer_email, order_date,
-                    status, total_amount, shipping_address, city, state, country,
-                    postal_code, payment_method, payment_status, shipping_method,
-                    tracking_number, notes
-                ) VALUES (
-                    $1::varchar, $2::int, $3::varchar, $4::varchar, $5::date,
-                    $6::varchar, $7::numeric, $8::varchar, $9::varchar, $10::varchar, $11::varchar,
-                    $12::varchar, $13::varchar, $14::varchar, $15::varchar, $16::varchar, $17::text
-                )
-                ON CONFLICT (order_number) DO NOTHING",
-            )
+        // A batch is safe to repeat: rows that made it before a failure are
+        // skipped by ON CONFLICT, so a retry only fills in the rest
+        let batch_start_time = Instant::now();
+        let batch_inserted = pool
+            .with_retry("Inserting batch", || insert_batch(&pool, batch_start, batch_end))
             .await?;
-        
-        for i in batch_start..batch_end {
-            let order_number = format!("ORD-{:08}", i + 1);
-            let customer_id = rand::thread_rng().gen_range(1..=100000);
-            let customer_name = generate_name();
-            let customer_email = format!("customer{}@example.com", customer_id);
-            let order_date = generate_random_date();
-            let status = generate_status();
-            let total_amount = Decimal::from_f64(rand::thread_rng().gen_range(10.0..=5000.0))
-                .unwrap_or_else(|| Decimal::new(0, 0));
-            let shipping_address = generate_address();
-            let city = generate_city();
-            let state = generate_state();
-            let country = generate_country();
-            let postal_code = generate_postal_code();
-            let payment_method = generate_payment_method();
-            let payment_status = generate_payment_status();
-            let shipping_method = generate_shipping_method();
-            let tracking_number = if rand::thread_rng().gen_bool(0.8) {
-                Some(format!("TRACK{:012}", rand::thread_rng().gen_range(100000000000i64..999999999999i64)))
-            } else {
-                None
-            };
-            let notes = if rand::thread_rng().gen_bool(0.3) {
-                Some(generate_notes())
-            } else {
-                None
-            };
-
-            let exec_result = client
-                .execute(
-                    &stmt,
-                    &[
-                        &order_number,
-                        &(customer_id as i32),
-                        &customer_name,
-                        &customer_email,
-                        &order_date,
-                        &status,
-                        &total_amount,
-                        &shipping_address,
-                        &city,
-                        &state,
-                        &country,
-                        &postal_code,
-                        &payment_method,
-                        &payment_status,
-                        &shipping_method,
-                        &tracking_number.as_deref(),
-                        &notes.as_deref(),
-                    ],
-                )
-                .await?;
-
-            if exec_result > 0 {
-                inserted += exec_result as usize;
-            }
-        }
+        inserted += batch_inserted as usize;
+        metrics.seed_rows.inc_by(batch_inserted);
+        metrics.seed_batch_duration.observe_duration(batch_start_time.elapsed());
 
         if batch_end % 10000 == 0 || batch_end == total_rows {
-            println!("Inserted {} / {} rows", batch_end, total_rows);
+            tracing::info!("Inserted {} / {} rows", batch_end, total_rows);
         }
     }
 
-    println!("Successfully inserted {} rows", inserted);
-    Ok(())
+    let rows_per_second = inserted as f64 / start.elapsed().as_secs_f64().max(0.001);
+    metrics.seed_rows_per_second.set(rows_per_second as i64);
+    tracing::info!(inserted, rows_per_second = rows_per_second as u64, "Seeding completed");
+    Ok(inserted as u64)
 }
 
+/// Insert orders `batch_start..batch_end`, returning how many were new.
+async fn insert_batch(
+    pool: &DbPool,
+    batch_start: usize,
+    batch_end: usize,
+) -> Result<u64, tokio_postgres::Error> {
+    // Prepare statement per batch to ensure it's on the same connection
+    let client = pool.get_client().await?;
+    let stmt = client
+        .prepare(
+            "INSERT INTO orders (
+                order_number, customer_id, customer_name, customer_email, order_date,
+                status, total_amount, shipping_address, city, state, country,
+                postal_code, payment_method, payment_status, shipping_method,
+                tracking_number, notes
+            ) VALUES (
+                $1::varchar, $2::int, $3::varchar, $4::varchar, $5::date,
+                $6::varchar, $7::numeric, $8::varchar, $9::varchar, $10::varchar, $11::varchar,
+                $12::varchar, $13::varchar, $14::varchar, $15::varchar, $16::varchar, $17::text
+            )
+            ON CONFLICT (order_number) DO NOTHING",
+        )
+        .await?;
+
+    let mut inserted = 0;
+    for i in batch_start..batch_end {
+        let order_number = format!("ORD-{:08}", i + 1);
+        let customer_id = rand::thread_rng().gen_range(1..=100000);
+        let customer_name = generate_name();
+        let customer_email = format!("customer{}@example.com", customer_id);
+        let order_date = generate_random_date();
+        let status = generate_status();
+        let total_amount = Decimal::from_f64(rand::thread_rng().gen_range(10.0..=5000.0))
+            .unwrap_or_else(|| Decimal::new(0, 0));
+        let shipping_address = generate_address();
+        let city = generate_city();
+        let state = generate_state();
+        let country = generate_country();
+        let postal_code = generate_postal_code();
+        let payment_method = generate_payment_method();
+        let payment_status = generate_payment_status();
+        let shipping_method = generate_shipping_method();
+        let tracking_number = if rand::thread_rng().gen_bool(0.8) {
+            Some(format!("TRACK{:012}", rand::thread_rng().gen_range(100000000000i64..999999999999i64)))
+        } else {
+            None
+        };
+        let notes = if rand::thread_rng().gen_bool(0.3) {
+            Some(generate_notes())
+        } else {
+            None
+        };
+
+        let exec_result = client
+            .execute(
+                &stmt,
+                &[
+                    &order_number,
+                    &(customer_id as i32),
+                    &customer_name,
+                    &customer_email,
+                    &order_date,
+                    &status,
+                    &total_amount,
+                    &shipping_address,
+                    &city,
+                    &state,
+                    &country,
+                    &postal_code,
+                    &payment_method,
+                    &payment_status,
+                    &shipping_method,
+                    &tracking_number.as_deref(),
+                    &notes.as_deref(),
+                ],
+            )
+            .await?;
+
+        inserted += exec_result;
+    }
+
+    Ok(inserted)
+}
 
 fn generate_name() -> String {
     let first_names = ["John", "Jane", "Michael", "Sarah", "David", "Emily", "Robert", "Jessica", "William", "Ashley"];
@@ -0,0 +1,611 @@
+//! Long-running work started by the server (exports, report runs, seeding),
+//! tracked so that shutdown can drain it and recorded for auditing.
+//!
+//! Each job is recorded in `export_jobs`: who asked for it and with what
+//! parameters when it starts, then its status, row count, file size, storage
+//! locations or error when it ends. [`Jobs::list`] serves the history behind
+//! `GET /exports`.
+//!
+//! On shutdown the server stops taking new jobs ([`Jobs::drain`]), gives
+//! running ones `SERVER_SHUTDOWN_GRACE_SECS` to finish, then cancels the rest
+//! ([`Jobs::cancel`]). A cancelled job is dropped where it stands: its
+//! partial file is removed and its open transaction rolled back. Jobs that a
+//! server killed outright left `running` are marked `failed` by the next
+//! server to start ([`Jobs::recover`]); they are not resumed.
+
+use crate::db::{instance_id, DbPool, APPLICATION_NAME_PREFIX};
+use crate::error::AppError;
+use crate::export::ExportedFile;
+use crate::storage::StoredExport;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobKind {
+    Export,
+    Report,
+    ScheduledReport,
+    Seed,
+}
+
+impl JobKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobKind::Export => "export",
+            JobKind::Report => "report",
+            JobKind::ScheduledReport => "scheduled_report",
+            JobKind::Seed => "seed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "running" => Ok(JobStatus::Running),
+            "succeeded" => Ok(JobStatus::Succeeded),
+            "failed" => Ok(JobStatus::Failed),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            other => Err(format!(
+                "unknown status '{}'; expected running, succeeded, failed or cancelled",
+                other
+            )),
+        }
+    }
+}
+
+/// Who started a job and with what, recorded when it starts.
+#[derive(Debug, Clone)]
+pub struct JobRequest {
+    pub kind: JobKind,
+    /// The report, for report runs.
+    pub name: Option<String>,
+    /// Principal name, or `scheduler` for scheduled runs.
+    pub requested_by: String,
+    /// The request's options. Passwords are never included.
+    pub parameters: serde_json::Value,
+}
+
+/// What a finished job produced, recorded when it ends.
+#[derive(Debug, Clone, Default)]
+pub struct JobOutput {
+    pub rows: Option<u64>,
+    pub bytes: Option<u64>,
+    pub locations: Vec<String>,
+}
+
+impl JobOutput {
+    /// Count one exported file, now in storage as `stored`.
+    pub fn add(&mut self, file: &ExportedFile, stored: &StoredExport) {
+        *self.rows.get_or_insert(0) += file.rows;
+        *self.bytes.get_or_insert(0) += stored.size;
+        self.locations.push(stored.location.clone());
+    }
+}
+
+/// One row of `export_jobs`, as listed by `GET /exports`.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobRecord {
+    pub id: i64,
+    pub kind: String,
+    pub name: Option<String>,
+    pub requested_by: Option<String>,
+    pub parameters: serde_json::Value,
+    pub status: String,
+    pub row_count: Option<i64>,
+    pub file_size: Option<i64>,
+    /// `None` while the job is running.
+    pub duration_ms: Option<i64>,
+    pub error: Option<String>,
+    pub locations: Vec<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Which jobs [`Jobs::list`] returns, newest first.
+#[derive(Debug, Clone)]
+pub struct JobFilter {
+    pub status: Option<JobStatus>,
+    /// Jobs started at or after this time.
+    pub from: Option<DateTime<Utc>>,
+    /// Jobs started before this time.
+    pub to: Option<DateTime<Utc>>,
+    /// Only jobs of this principal.
+    pub requested_by: Option<String>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// A page of [`Jobs::list`]; `total` counts every job matching the filter.
+#[derive(Debug, Serialize)]
+pub struct JobPage {
+    pub jobs: Vec<JobRecord>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+    /// Offset of the next page; `None` on the last one.
+    pub next_offset: Option<i64>,
+}
+
+/// Page size of `GET /exports` without `limit`, and the largest allowed.
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// Parse a `from`/`to` bound: an RFC 3339 time, or a `YYYY-MM-DD` date
+/// meaning midnight UTC at its start, or with `end_of_day` at its end.
+pub fn parse_time(value: &str, end_of_day: bool) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+        format!(
+            "invalid time '{}'; expected RFC 3339 (2026-10-01T00:00:00Z) or YYYY-MM-DD",
+            value
+        )
+    })?;
+    let date = if end_of_day {
+        date.succ_opt().unwrap_or(date)
+    } else {
+        date
+    };
+    Ok(date.and_hms_opt(0, 0, 0).expect("midnight exists").and_utc())
+}
+
+/// A job a previous server left unfinished, as found by [`Jobs::recover`].
+#[derive(Debug, Clone)]
+pub struct InterruptedJob {
+    pub id: i64,
+    pub kind: String,
+    pub name: Option<String>,
+    pub started_at: DateTime<Utc>,
+}
+
+/// Connections open on the database, from `pg_stat_activity`.
+#[derive(Debug, Default)]
+struct LiveConnections {
+    pids: HashSet<i32>,
+    application_names: HashSet<String>,
+}
+
+impl LiveConnections {
+    fn from_rows(rows: &[tokio_postgres::Row]) -> Self {
+        let mut live = LiveConnections::default();
+        for row in rows {
+            live.pids.insert(row.get(0));
+            if let Some(name) = row.get::<_, Option<String>>(1) {
+                live.application_names.insert(name);
+            }
+        }
+        live
+    }
+
+    /// Whether the server that recorded a job is still connected. A server
+    /// is gone once no connection carries its instance id (see
+    /// [`crate::db::instance_id`]); jobs recorded before instance ids fall
+    /// back to the backend pid of the server's connection.
+    fn has_server(&self, server_instance: Option<&str>, server_pid: Option<i32>) -> bool {
+        match (server_instance, server_pid) {
+            (Some(instance), _) => self
+                .application_names
+                .contains(&format!("{}{}", APPLICATION_NAME_PREFIX, instance)),
+            (None, Some(pid)) => self.pids.contains(&pid),
+            (None, None) => false,
+        }
+    }
+}
+
+/// The running jobs of this server.
+pub struct Jobs {
+    pool: Arc<DbPool>,
+    retry_after: Duration,
+    draining: AtomicBool,
+    running: watch::Sender<usize>,
+    cancelled: watch::Sender<bool>,
+    /// Set once recording a job has failed, so the warning is logged once.
+    unrecorded: AtomicBool,
+}
+
+impl Jobs {
+    /// `retry_after` is sent with the `503` for jobs refused while draining.
+    pub fn new(pool: Arc<DbPool>, retry_after: Duration) -> Arc<Self> {
+        Arc::new(Jobs {
+            pool,
+            retry_after,
+            draining: AtomicBool::new(false),
+            running: watch::channel(0).0,
+            cancelled: watch::channel(false).0,
+            unrecorded: AtomicBool::new(false),
+        })
+    }
+
+    /// Run `work` as the job `request`, recording the [`JobOutput`] it
+    /// returns alongside its result. Fails with [`AppError::Unavailable`]
+    /// once the server is draining, and with [`AppError::Cancelled`] if the
+    /// job is cancelled before it finishes.
+    pub async fn run<T>(
+        &self,
+        request: JobRequest,
+        work: impl Future<Output = Result<(T, JobOutput), AppError>>,
+    ) -> Result<T, AppError> {
+        // Counted before the check, so `wait_idle` cannot miss a job that
+        // got past it.
+        let mut job = RunningJob::new(self);
+        if self.is_draining() {
+            return Err(AppError::Unavailable(
+                "the server is shutting down".to_string(),
+                self.retry_after,
+            ));
+        }
+        job.id = self.record_start(&request).await;
+        tracing::debug!(job_id = job.id, kind = request.kind.as_str(), "job started");
+
+        let mut cancelled = self.cancelled.subscribe();
+        let result = tokio::select! {
+            // Work that is done wins over a cancel that arrived meanwhile.
+            biased;
+            result = work => result,
+            _ = cancelled.wait_for(|c| *c) => Err(AppError::Cancelled(
+                "the server shut down before the job finished".to_string(),
+            )),
+        };
+        job.finish(&result).await;
+        result.map(|(value, _)| value)
+    }
+
+    /// Refuse new jobs from now on.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Stop every running job.
+    pub fn cancel(&self) {
+        self.cancelled.send_replace(true);
+    }
+
+    /// Jobs in progress.
+    pub fn running(&self) -> usize {
+        *self.running.borrow()
+    }
+
+    /// Wait until no job is running.
+    pub async fn wait_idle(&self) {
+        let mut running = self.running.subscribe();
+        let _ = running.wait_for(|n| *n == 0).await;
+    }
+
+    /// Mark jobs left `running` by a server that is gone as `failed` (see
+    /// [`LiveConnections::has_server`]).
+    pub async fn recover(&self) -> Result<Vec<InterruptedJob>, AppError> {
+        let client = self.pool.get_client().await?;
+        // Jobs first: a job started after this read is not considered, and
+        // its server is connected by the time connections are read.
+        let running = client
+            .query(
+                "SELECT id, server_instance::text, server_pid
+                 FROM export_jobs WHERE status = 'running'",
+                &[],
+            )
+            .await?;
+        if running.is_empty() {
+            return Ok(Vec::new());
+        }
+        let live = LiveConnections::from_rows(
+            &client
+                .query("SELECT pid, application_name FROM pg_stat_activity", &[])
+                .await?,
+        );
+        let gone: Vec<i64> = running
+            .iter()
+            .filter(|row| !live.has_server(row.get(1), row.get(2)))
+            .map(|row| row.get(0))
+            .collect();
+
+        let rows = client
+            .query(
+                "UPDATE export_jobs
+                 SET status = 'failed', error = $1, finished_at = now()
+                 WHERE id = ANY($2) AND status = 'running'
+                 RETURNING id, kind, name, started_at",
+                &[
+                    &"interrupted: the server stopped before the job finished",
+                    &gone,
+                ],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| InterruptedJob {
+                id: row.get(0),
+                kind: row.get(1),
+                name: row.get(2),
+                started_at: row.get(3),
+            })
+            .collect())
+    }
+
+    /// Recorded jobs matching `filter`, newest first.
+    pub async fn list(&self, filter: &JobFilter) -> Result<JobPage, AppError> {
+        const WHERE: &str = "WHERE ($1::text IS NULL OR status = $1)
+              AND ($2::timestamptz IS NULL OR started_at >= $2)
+              AND ($3::timestamptz IS NULL OR started_at < $3)
+              AND ($4::text IS NULL OR requested_by = $4)";
+        let status = filter.status.map(JobStatus::as_str);
+        let client = self.pool.get_client().await?;
+        let params: [&(dyn tokio_postgres::types::ToSql + Sync); 4] =
+            [&status, &filter.from, &filter.to, &filter.requested_by];
+
+        let total: i64 = client
+            .query_one(&format!("SELECT count(*) FROM export_jobs {}", WHERE), &params)
+            .await?
+            .get(0);
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT id, kind, name, requested_by, parameters, status, row_count,
+                            file_size,
+                            (EXTRACT(EPOCH FROM finished_at - started_at) * 1000)::bigint,
+                            error, locations, started_at, finished_at
+                     FROM export_jobs {}
+                     ORDER BY started_at DESC, id DESC
+                     LIMIT $5 OFFSET $6",
+                    WHERE
+                ),
+                &[
+                    params[0],
+                    params[1],
+                    params[2],
+                    params[3],
+                    &filter.limit,
+                    &filter.offset,
+                ],
+            )
+            .await?;
+
+        let jobs: Vec<JobRecord> = rows
+            .iter()
+            .map(|row| JobRecord {
+                id: row.get(0),
+                kind: row.get(1),
+                name: row.get(2),
+                requested_by: row.get(3),
+                parameters: row.get(4),
+                status: row.get(5),
+                row_count: row.get(6),
+                file_size: row.get(7),
+                duration_ms: row.get(8),
+                error: row.get(9),
+                locations: row.get(10),
+                started_at: row.get(11),
+                finished_at: row.get(12),
+            })
+            .collect();
+        let end = filter.offset + jobs.len() as i64;
+        Ok(JobPage {
+            next_offset: (end < total).then_some(end),
+            jobs,
+            total,
+            limit: filter.limit,
+            offset: filter.offset,
+        })
+    }
+
+    /// Insert the job's row; `None` if it could not be recorded, in which
+    /// case the job runs untracked.
+    async fn record_start(&self, request: &JobRequest) -> Option<i64> {
+        let inserted = async {
+            let client = self.pool.get_client().await?;
+            client
+                .query_one(
+                    "INSERT INTO export_jobs (kind, name, requested_by, parameters, server_instance)
+                     VALUES ($1, $2, $3, $4, $5::text::uuid)
+                     RETURNING id",
+                    &[
+                        &request.kind.as_str(),
+                        &request.name,
+                        &request.requested_by,
+                        &request.parameters,
+                        &instance_id(),
+                    ],
+                )
+                .await
+        }
+        .await;
+        match inserted {
+            Ok(row) => Some(row.get(0)),
+            Err(e) => {
+                if !self.unrecorded.swap(true, Ordering::SeqCst) {
+                    tracing::warn!(
+                        error = %crate::retry::describe(&e),
+                        "Jobs: cannot record job state; is the schema migrated?"
+                    );
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Counts as running until dropped, and records how the job ended. A job
+/// dropped before [`RunningJob::finish`] (its request went away) is recorded
+/// as cancelled in the background.
+struct RunningJob<'a> {
+    jobs: &'a Jobs,
+    id: Option<i64>,
+}
+
+impl<'a> RunningJob<'a> {
+    fn new(jobs: &'a Jobs) -> Self {
+        jobs.running.send_modify(|n| *n += 1);
+        RunningJob { jobs, id: None }
+    }
+
+    async fn finish<T>(&mut self, result: &Result<(T, JobOutput), AppError>) {
+        let Some(id) = self.id.take() else {
+            return;
+        };
+        let none = JobOutput::default();
+        let (status, error, output) = match result {
+            Ok((_, output)) => (JobStatus::Succeeded, None, output),
+            Err(AppError::Cancelled(msg)) => (JobStatus::Cancelled, Some(msg.clone()), &none),
+            Err(e) => (JobStatus::Failed, Some(e.to_string()), &none),
+        };
+        record_end(&self.jobs.pool, id, status, error, output).await;
+    }
+}
+
+impl Drop for RunningJob<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            let pool = self.jobs.pool.clone();
+            tokio::spawn(async move {
+                let error = "the request was abandoned before the job finished";
+                let output = JobOutput::default();
+                record_end(&pool, id, JobStatus::Cancelled, Some(error.to_string()), &output)
+                    .await;
+            });
+        }
+        self.jobs.running.send_modify(|n| *n -= 1);
+    }
+}
+
+async fn record_end(
+    pool: &DbPool,
+    id: i64,
+    status: JobStatus,
+    error: Option<String>,
+    output: &JobOutput,
+) {
+    let updated = async {
+        let client = pool.get_client().await?;
+        client
+            .execute(
+                "UPDATE export_jobs
+                 SET status = $2, error = $3, row_count = $4, file_size = $5, locations = $6,
+                     finished_at = now()
+                 WHERE id = $1",
+                &[
+                    &id,
+                    &status.as_str(),
+                    &error,
+                    &output.rows.map(|n| n as i64),
+                    &output.bytes.map(|n| n as i64),
+                    &output.locations,
+                ],
+            )
+            .await
+    }
+    .await;
+    if let Err(e) = updated {
+        tracing::warn!(
+            job_id = id,
+            error = %crate::retry::describe(&e),
+            "Jobs: failed to record job result"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const OTHER_INSTANCE: &str = "2f1d6c8e-5b7a-4c3d-9e0f-1a2b3c4d5e6f";
+
+    fn live() -> LiveConnections {
+        LiveConnections {
+            pids: [101, 102].into(),
+            application_names: [
+                format!("{}{}", APPLICATION_NAME_PREFIX, instance_id()),
+                "psql".to_string(),
+                String::new(),
+            ]
+            .into(),
+        }
+    }
+
+    #[test]
+    fn only_jobs_of_servers_that_are_gone_are_recovered() {
+        let live = live();
+        // This server is connected, so its jobs are left alone whatever pid
+        // they were recorded with.
+        assert!(live.has_server(Some(instance_id()), None));
+        assert!(live.has_server(Some(instance_id()), Some(999)));
+        // Another server's instance id counts even if its old pid was reused.
+        assert!(!live.has_server(Some(OTHER_INSTANCE), None));
+        assert!(!live.has_server(Some(OTHER_INSTANCE), Some(101)));
+        // Without the prefix, an application name is not a server.
+        assert!(!live.has_server(Some("psql"), None));
+        // Jobs from before instance ids go by backend pid.
+        assert!(live.has_server(None, Some(102)));
+        assert!(!live.has_server(None, Some(103)));
+        assert!(!live.has_server(None, None));
+    }
+
+    #[test]
+    fn status_filter_must_be_known() {
+        for status in [
+            JobStatus::Running,
+            JobStatus::Succeeded,
+            JobStatus::Failed,
+            JobStatus::Cancelled,
+        ] {
+            assert_eq!(JobStatus::parse(status.as_str()), Ok(status));
+        }
+        assert!(JobStatus::parse("done").unwrap_err().contains("'done'"));
+        assert!(JobStatus::parse("Failed").is_err());
+        assert!(JobStatus::parse("").is_err());
+    }
+
+    #[test]
+    fn time_bounds_accept_rfc3339_and_dates() {
+        assert_eq!(
+            parse_time("2026-10-01T12:30:00+02:00", false),
+            Ok(Utc.with_ymd_and_hms(2026, 10, 1, 10, 30, 0).unwrap())
+        );
+        assert_eq!(
+            parse_time("2026-10-01", false),
+            Ok(Utc.with_ymd_and_hms(2026, 10, 1, 0, 0, 0).unwrap())
+        );
+        // `to` dates include the whole day, across month ends.
+        assert_eq!(
+            parse_time("2026-10-31", true),
+            Ok(Utc.with_ymd_and_hms(2026, 11, 1, 0, 0, 0).unwrap())
+        );
+        // An explicit time is exact, even for `to`.
+        assert_eq!(
+            parse_time("2026-10-31T00:00:00Z", true),
+            Ok(Utc.with_ymd_and_hms(2026, 10, 31, 0, 0, 0).unwrap())
+        );
+        for bad in ["yesterday", "2026-13-01", "2026-10-01 12:00", "1696118400"] {
+            assert!(parse_time(bad, false).is_err(), "{}", bad);
+        }
+    }
+}
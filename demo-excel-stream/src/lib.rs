@@ -1,6 +1,29 @@
+pub mod auth;
+pub mod checkpoint;
 pub mod config;
 pub mod db;
 pub mod error;
 pub mod export;
+pub mod health;
 pub mod insert_data;
-
+pub mod jobs;
+pub mod limits;
+pub mod metrics;
+pub mod migrate;
+pub mod output;
+pub mod protection;
+pub mod query;
+pub mod redaction;
+pub mod reports;
+pub mod request_id;
+pub mod retention;
+pub mod retry;
+pub mod row;
+pub mod scheduler;
+pub mod storage;
+pub mod style;
+pub mod summary;
+pub mod telemetry;
+pub mod template;
+pub mod timeouts;
+pub mod xlsx_patch;
@@ -0,0 +1,211 @@
+//! Back-pressure for the HTTP API: a cap on concurrent exports (global and
+//! per client) and a per-client request rate limit.
+//!
+//! Exports share one database connection, so beyond the global cap a request
+//! waits briefly for a slot and then gets `503`; a client over its own cap or
+//! request rate gets `429`. Both carry `Retry-After`.
+
+use crate::auth::Principal;
+use crate::config::Config;
+use crate::error::AppError;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Idle rate-limit buckets are pruned once this many clients are tracked.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Identifies a caller for the per-client limits: the principal, or its peer
+/// address when authentication is disabled. (Forwarding headers are ignored,
+/// since clients can set them freely.)
+pub fn client_id(principal: &Principal, peer: Option<&str>) -> String {
+    if principal.is_anonymous() {
+        format!("ip:{}", peer.unwrap_or("unknown"))
+    } else {
+        principal.name.clone()
+    }
+}
+
+/// Limits how many exports run at once.
+pub struct ExportLimiter {
+    global: Arc<Semaphore>,
+    capacity: usize,
+    per_client: usize,
+    queue_timeout: Duration,
+    retry_after: Duration,
+    active: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// Held for the duration of one export.
+pub struct ExportPermit {
+    _global: OwnedSemaphorePermit,
+    _client: Option<ClientSlot>,
+}
+
+/// One of a client's export slots; released on drop.
+struct ClientSlot {
+    active: Arc<Mutex<HashMap<String, usize>>>,
+    client: String,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.client);
+            }
+        }
+    }
+}
+
+impl ExportLimiter {
+    pub fn new(config: &Config) -> Self {
+        ExportLimiter {
+            global: Arc::new(Semaphore::new(config.export_max_concurrent)),
+            capacity: config.export_max_concurrent,
+            per_client: config.export_max_per_client,
+            queue_timeout: config.export_queue_timeout,
+            retry_after: config.export_retry_after,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Take an export slot for `client`, waiting up to the queue timeout for
+    /// a global slot.
+    pub async fn acquire(&self, client: &str) -> Result<ExportPermit, AppError> {
+        let slot = {
+            let mut active = self.active.lock().unwrap();
+            let count = active.entry(client.to_string()).or_insert(0);
+            if *count >= self.per_client {
+                return Err(AppError::TooManyRequests(
+                    format!("{} export(s) already running for this client", *count),
+                    self.retry_after,
+                ));
+            }
+            *count += 1;
+            ClientSlot {
+                active: self.active.clone(),
+                client: client.to_string(),
+            }
+        };
+
+        let permit = tokio::time::timeout(self.queue_timeout, self.global.clone().acquire_owned())
+            .await
+            .map_err(|_| {
+                AppError::Unavailable("too many exports in progress".to_string(), self.retry_after)
+            })?
+            .expect("export semaphore is never closed");
+
+        Ok(ExportPermit {
+            _global: permit,
+            _client: Some(slot),
+        })
+    }
+
+    /// Export slots in total (`EXPORT_MAX_CONCURRENT`).
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Export slots currently taken.
+    pub fn running(&self) -> usize {
+        self.capacity - self.global.available_permits()
+    }
+
+    /// Take a global slot for background work (scheduled reports), waiting
+    /// as long as it takes.
+    pub async fn acquire_background(&self) -> ExportPermit {
+        ExportPermit {
+            _global: self
+                .global
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("export semaphore is never closed"),
+            _client: None,
+        }
+    }
+}
+
+/// Token bucket per client: `burst` requests at once, refilled at `rate` per
+/// second.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// `None` when `RATE_LIMIT_PER_MINUTE` is 0.
+    pub fn new(config: &Config) -> Option<Self> {
+        (config.rate_limit_per_minute > 0).then(|| RateLimiter {
+            rate: config.rate_limit_per_minute as f64 / 60.0,
+            burst: config.rate_limit_burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Take one token for `client`, or return how long until one is free.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+/// Actix middleware: apply the request rate limit. Runs after
+/// `auth::middleware`, so the caller is known.
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let limiter = req
+        .app_data::<web::Data<Option<Arc<RateLimiter>>>>()
+        .and_then(|data| data.get_ref().clone());
+    if let Some(limiter) = limiter {
+        let client = {
+            let extensions = req.extensions();
+            let principal = extensions
+                .get::<Principal>()
+                .ok_or_else(|| AppError::Unauthorized("authentication required".to_string()))?;
+            let peer = req.peer_addr().map(|addr| addr.ip().to_string());
+            client_id(principal, peer.as_deref())
+        };
+        if let Err(wait) = limiter.check(&client) {
+            return Err(AppError::TooManyRequests("rate limit exceeded".to_string(), wait).into());
+        }
+    }
+    next.call(req).await
+}
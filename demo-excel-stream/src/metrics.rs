@@ -0,0 +1,428 @@
+//! Prometheus metrics for the server, served as text by `GET /metrics`.
+//!
+//! A small registry of counters, gauges and histograms, some split by
+//! labels. Instrumented code records into the process-wide [`metrics()`];
+//! label values must come from small fixed sets (route patterns, formats,
+//! outcomes), never from user input.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+const HTTP_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+const EXPORT_BUCKETS: &[f64] = &[
+    0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+const SEED_BATCH_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const POOL_WAIT_BUCKETS: &[f64] = &[
+    0.0001, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0,
+];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, delta: i64) {
+        self.0.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Observations counted into fixed buckets, Prometheus style.
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Per bucket, not cumulative; the last one is `+Inf`.
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// `f64` bits.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let idx = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+}
+
+/// One metric per combination of label values.
+pub struct Family<M> {
+    labels: &'static [&'static str],
+    new: fn() -> M,
+    series: Mutex<BTreeMap<Vec<String>, Arc<M>>>,
+}
+
+impl<M> Family<M> {
+    fn new(labels: &'static [&'static str], new: fn() -> M) -> Self {
+        Family {
+            labels,
+            new,
+            series: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The metric for `values`, given in the order of the family's labels.
+    pub fn with(&self, values: &[&str]) -> Arc<M> {
+        debug_assert_eq!(values.len(), self.labels.len());
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        let mut series = self.series.lock().unwrap();
+        series
+            .entry(key)
+            .or_insert_with(|| Arc::new((self.new)()))
+            .clone()
+    }
+
+    fn label_set(&self, values: &[String], extra: Option<(&str, &str)>) -> String {
+        let pairs: Vec<String> = self
+            .labels
+            .iter()
+            .zip(values)
+            .map(|(name, value)| (*name, value.as_str()))
+            .chain(extra)
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect();
+        if pairs.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", pairs.join(","))
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Everything the server measures.
+pub struct Metrics {
+    /// method, route, status
+    pub http_requests: Family<Counter>,
+    /// method, route
+    pub http_duration: Family<Histogram>,
+    /// format, outcome (`success`, `timeout`, `error`)
+    pub exports: Family<Counter>,
+    /// format
+    pub export_rows: Family<Counter>,
+    /// format
+    pub export_bytes: Family<Counter>,
+    /// format
+    pub export_duration: Family<Histogram>,
+    pub seed_rows: Counter,
+    pub seed_batch_duration: Histogram,
+    /// Throughput of the last finished seeding run.
+    pub seed_rows_per_second: Gauge,
+    pub db_pool_max: Gauge,
+    pub db_pool_connections: Gauge,
+    pub db_pool_in_use: Gauge,
+    pub db_pool_waiting: Gauge,
+    pub db_pool_wait: Histogram,
+    pub db_reconnects: Counter,
+    pub db_retries: Counter,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics {
+    http_requests: Family::new(&["method", "route", "status"], Counter::default),
+    http_duration: Family::new(&["method", "route"], || Histogram::new(HTTP_BUCKETS)),
+    exports: Family::new(&["format", "outcome"], Counter::default),
+    export_rows: Family::new(&["format"], Counter::default),
+    export_bytes: Family::new(&["format"], Counter::default),
+    export_duration: Family::new(&["format"], || Histogram::new(EXPORT_BUCKETS)),
+    seed_rows: Counter::default(),
+    seed_batch_duration: Histogram::new(SEED_BATCH_BUCKETS),
+    seed_rows_per_second: Gauge::default(),
+    db_pool_max: Gauge::default(),
+    db_pool_connections: Gauge::default(),
+    db_pool_in_use: Gauge::default(),
+    db_pool_waiting: Gauge::default(),
+    db_pool_wait: Histogram::new(POOL_WAIT_BUCKETS),
+    db_reconnects: Counter::default(),
+    db_retries: Counter::default(),
+});
+
+/// The process-wide metrics.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    /// The Prometheus text exposition format (version 0.0.4).
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render_counters(
+            &mut out,
+            "http_requests_total",
+            "HTTP requests by route and status.",
+            &self.http_requests,
+        );
+        render_histograms(
+            &mut out,
+            "http_request_duration_seconds",
+            "HTTP request latency.",
+            &self.http_duration,
+        );
+        render_counters(
+            &mut out,
+            "exports_total",
+            "Finished exports by format and outcome.",
+            &self.exports,
+        );
+        render_counters(
+            &mut out,
+            "export_rows_total",
+            "Rows written by successful exports.",
+            &self.export_rows,
+        );
+        render_counters(
+            &mut out,
+            "export_bytes_total",
+            "Bytes written by successful exports.",
+            &self.export_bytes,
+        );
+        render_histograms(
+            &mut out,
+            "export_duration_seconds",
+            "Duration of successful exports.",
+            &self.export_duration,
+        );
+        render_counter(
+            &mut out,
+            "seed_rows_total",
+            "Rows inserted by /insert-data.",
+            &self.seed_rows,
+        );
+        render_histogram(
+            &mut out,
+            "seed_batch_duration_seconds",
+            "Duration of one seeding batch.",
+            &self.seed_batch_duration,
+        );
+        render_gauge(
+            &mut out,
+            "seed_rows_per_second",
+            "Throughput of the last seeding run.",
+            &self.seed_rows_per_second,
+        );
+        render_gauge(
+            &mut out,
+            "db_pool_max_connections",
+            "Largest number of connections the database pool opens (DB_POOL_SIZE).",
+            &self.db_pool_max,
+        );
+        render_gauge(
+            &mut out,
+            "db_pool_connections",
+            "Open connections in the database pool.",
+            &self.db_pool_connections,
+        );
+        render_gauge(
+            &mut out,
+            "db_pool_connections_in_use",
+            "Pool connections checked out.",
+            &self.db_pool_in_use,
+        );
+        render_gauge(
+            &mut out,
+            "db_pool_waiting",
+            "Tasks waiting for a pool connection.",
+            &self.db_pool_waiting,
+        );
+        render_histogram(
+            &mut out,
+            "db_pool_wait_seconds",
+            "Time spent waiting for a pool connection.",
+            &self.db_pool_wait,
+        );
+        render_counter(
+            &mut out,
+            "db_reconnects_total",
+            "Pool connections dropped after they closed; the next checkout reconnects.",
+            &self.db_reconnects,
+        );
+        render_counter(
+            &mut out,
+            "db_retries_total",
+            "Database operations retried after a transient failure.",
+            &self.db_retries,
+        );
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, counter.0.load(Ordering::Relaxed));
+}
+
+fn render_gauge(out: &mut String, name: &str, help: &str, gauge: &Gauge) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, gauge.0.load(Ordering::Relaxed));
+}
+
+fn render_counters(out: &mut String, name: &str, help: &str, family: &Family<Counter>) {
+    header(out, name, help, "counter");
+    for (values, counter) in family.series.lock().unwrap().iter() {
+        let labels = family.label_set(values, None);
+        let _ = writeln!(
+            out,
+            "{}{} {}",
+            name,
+            labels,
+            counter.0.load(Ordering::Relaxed)
+        );
+    }
+}
+
+fn render_histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    header(out, name, help, "histogram");
+    write_histogram(out, name, histogram, |le| match le {
+        Some(le) => format!("{{le=\"{}\"}}", le),
+        None => String::new(),
+    });
+}
+
+fn render_histograms(out: &mut String, name: &str, help: &str, family: &Family<Histogram>) {
+    header(out, name, help, "histogram");
+    for (values, histogram) in family.series.lock().unwrap().iter() {
+        write_histogram(out, name, histogram, |le| {
+            family.label_set(values, le.as_deref().map(|le| ("le", le)))
+        });
+    }
+}
+
+/// Buckets (cumulative), sum and count; `labels(Some(le))` labels a bucket,
+/// `labels(None)` the sum and count.
+fn write_histogram(
+    out: &mut String,
+    name: &str,
+    histogram: &Histogram,
+    labels: impl Fn(Option<String>) -> String,
+) {
+    let mut cumulative = 0;
+    for (i, bucket) in histogram.buckets.iter().enumerate() {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let le = match histogram.bounds.get(i) {
+            Some(bound) => bound.to_string(),
+            None => "+Inf".to_string(),
+        };
+        let _ = writeln!(out, "{}_bucket{} {}", name, labels(Some(le)), cumulative);
+    }
+    let sum = f64::from_bits(histogram.sum.load(Ordering::Relaxed));
+    let _ = writeln!(out, "{}_sum{} {}", name, labels(None), sum);
+    let _ = writeln!(
+        out,
+        "{}_count{} {}",
+        name,
+        labels(None),
+        histogram.count.load(Ordering::Relaxed)
+    );
+}
+
+/// Clients may send any extension method, so only the standard ones get a
+/// series of their own.
+fn method_label(method: &actix_web::http::Method) -> &'static str {
+    use actix_web::http::Method;
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::PATCH => "PATCH",
+        Method::OPTIONS => "OPTIONS",
+        _ => "other",
+    }
+}
+
+/// Actix middleware counting and timing requests by route pattern (e.g.
+/// `/downloads/{key}`), so ids in paths do not create new series. Requests
+/// turned away before routing (unknown paths, failed authentication, rate
+/// limits) count under `unrouted`. Wrap it outside the request-id middleware
+/// to see final error statuses.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let start = Instant::now();
+    let method = method_label(req.method());
+    let result = next.call(req).await;
+
+    let (route, status) = match &result {
+        Ok(res) => (
+            res.request().match_pattern(),
+            res.status().as_u16().to_string(),
+        ),
+        Err(err) => (
+            None,
+            err.as_response_error().status_code().as_u16().to_string(),
+        ),
+    };
+    let route = route.unwrap_or_else(|| "unrouted".to_string());
+    let metrics = metrics();
+    metrics
+        .http_requests
+        .with(&[method, &route, &status])
+        .inc();
+    metrics
+        .http_duration
+        .with(&[method, &route])
+        .observe_duration(start.elapsed());
+    result
+}
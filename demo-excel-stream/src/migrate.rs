@@ -0,0 +1,249 @@
+//! Versioned schema migrations, embedded in the binary.
+//!
+//! Each file in `migrations/` is applied once, in version order, inside its
+//! own transaction, and recorded in `schema_migrations` with a checksum. An
+//! applied migration whose file has since changed is an error: fix forward
+//! with a new migration instead. An advisory lock keeps two instances
+//! starting at once from applying the same migration twice.
+
+use crate::error::AppError;
+use sha2::{Digest, Sha256};
+use tokio_postgres::Client;
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration, oldest first. Append only.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        sql: include_str!("../migrations/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "orders_status_index",
+        sql: include_str!("../migrations/0002_orders_status_index.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "export_jobs",
+        sql: include_str!("../migrations/0003_export_jobs.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "export_jobs_history",
+        sql: include_str!("../migrations/0004_export_jobs_history.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "export_jobs_instance",
+        sql: include_str!("../migrations/0005_export_jobs_instance.sql"),
+    },
+];
+
+/// Arbitrary key for `pg_advisory_lock`, shared by every instance.
+const LOCK_KEY: i64 = 0x6d69_6772_6174_6500;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version BIGINT PRIMARY KEY,
+    name TEXT NOT NULL,
+    checksum TEXT NOT NULL,
+    applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+)";
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+/// Where the database stands against [`MIGRATIONS`].
+#[derive(Debug, Default)]
+pub struct MigrationStatus {
+    /// Versions recorded in `schema_migrations`.
+    pub applied: Vec<i64>,
+    /// Known versions not yet applied, oldest first.
+    pub pending: Vec<i64>,
+    /// Applied versions this binary does not know, i.e. the database was
+    /// migrated by a newer release.
+    pub unknown: Vec<i64>,
+}
+
+/// Read `schema_migrations` without changing anything; a database that has
+/// never been migrated has every migration pending.
+pub async fn status(client: &Client) -> Result<MigrationStatus, AppError> {
+    let applied = if table_exists(client).await? {
+        applied(client).await?
+    } else {
+        Vec::new()
+    };
+    check_checksums(&applied)?;
+
+    let versions: Vec<i64> = applied.iter().map(|(version, _)| *version).collect();
+    Ok(MigrationStatus {
+        pending: MIGRATIONS
+            .iter()
+            .map(|m| m.version)
+            .filter(|v| !versions.contains(v))
+            .collect(),
+        unknown: versions
+            .iter()
+            .copied()
+            .filter(|v| !MIGRATIONS.iter().any(|m| m.version == *v))
+            .collect(),
+        applied: versions,
+    })
+}
+
+/// Apply every pending migration, returning the versions applied.
+pub async fn run(client: &mut Client) -> Result<Vec<i64>, AppError> {
+    client
+        .execute("SELECT pg_advisory_lock($1)", &[&LOCK_KEY])
+        .await?;
+    let result = apply_pending(client).await;
+    // Closing the session would release it too, but the connection lives on.
+    let unlocked = client
+        .execute("SELECT pg_advisory_unlock($1)", &[&LOCK_KEY])
+        .await;
+    let applied = result?;
+    unlocked?;
+    Ok(applied)
+}
+
+async fn apply_pending(client: &mut Client) -> Result<Vec<i64>, AppError> {
+    // Under the lock: another instance may have just finished.
+    if !table_exists(client).await? {
+        client.batch_execute(CREATE_TABLE).await?;
+    }
+    let done = applied(client).await?;
+    check_checksums(&done)?;
+
+    let mut applied_now = Vec::new();
+    for migration in MIGRATIONS {
+        if done
+            .iter()
+            .any(|(version, _)| *version == migration.version)
+        {
+            continue;
+        }
+        let tx = client.transaction().await?;
+        tx.batch_execute(migration.sql).await.map_err(|e| {
+            AppError::Config(format!(
+                "migration {} ({}) failed: {}",
+                migration.version,
+                migration.name,
+                crate::retry::describe(&e)
+            ))
+        })?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)",
+            &[&migration.version, &migration.name, &migration.checksum()],
+        )
+        .await?;
+        tx.commit().await?;
+        tracing::info!(
+            version = migration.version,
+            name = migration.name,
+            "Applied migration"
+        );
+        applied_now.push(migration.version);
+    }
+    Ok(applied_now)
+}
+
+async fn table_exists(client: &Client) -> Result<bool, AppError> {
+    let row = client
+        .query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])
+        .await?;
+    Ok(row.get(0))
+}
+
+async fn applied(client: &Client) -> Result<Vec<(i64, String)>, AppError> {
+    let rows = client
+        .query(
+            "SELECT version, checksum FROM schema_migrations ORDER BY version",
+            &[],
+        )
+        .await?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+fn check_checksums(applied: &[(i64, String)]) -> Result<(), AppError> {
+    for (version, checksum) in applied {
+        if let Some(migration) = MIGRATIONS.iter().find(|m| m.version == *version) {
+            if migration.checksum() != *checksum {
+                return Err(AppError::Config(format!(
+                    "migration {} ({}) was changed after it was applied",
+                    migration.version, migration.name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn versions_are_contiguous_from_one() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1, "{}", migration.name);
+        }
+    }
+
+    #[test]
+    fn every_file_is_registered() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+        let mut files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        let expected: Vec<String> = MIGRATIONS
+            .iter()
+            .map(|m| format!("{:04}_{}.sql", m.version, m.name))
+            .collect();
+        assert_eq!(files, expected);
+    }
+
+    #[test]
+    fn checksum_is_sha256_of_the_sql() {
+        let migration = Migration {
+            version: 1,
+            name: "test",
+            sql: "SELECT 1;",
+        };
+        assert_eq!(
+            migration.checksum(),
+            "17db4fd369edb9244b9f91d9aeed145c3d04ad8ba6e95d06247f07a63527d11a"
+        );
+    }
+
+    #[test]
+    fn changed_migrations_are_rejected() {
+        let current: Vec<(i64, String)> = MIGRATIONS
+            .iter()
+            .map(|m| (m.version, m.checksum()))
+            .collect();
+        assert!(check_checksums(&current).is_ok());
+
+        // versions from a newer release are reported by `status`, not here
+        let newer = vec![(MIGRATIONS.len() as i64 + 1, "abc".to_string())];
+        assert!(check_checksums(&newer).is_ok());
+
+        let changed = vec![(1, "0".repeat(64))];
+        let err = check_checksums(&changed).unwrap_err().to_string();
+        assert!(
+            err.contains("migration 1 (initial_schema) was changed"),
+            "{}",
+            err
+        );
+    }
+}
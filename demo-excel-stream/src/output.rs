@@ -0,0 +1,106 @@
+use rand::Rng;
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Export files currently being written by this process. The retention
+/// janitor never deletes these.
+static ACTIVE: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// A reserved, uniquely named output file. The file stays marked as active
+/// (protected from retention cleanup) until this guard is dropped.
+#[derive(Debug)]
+pub struct ExportFile {
+    path: PathBuf,
+}
+
+impl ExportFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ExportFile {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// Create `dir` if needed and atomically reserve a new
+/// `{prefix}_{timestamp}_{random}.{ext}` file in it.
+///
+/// The file is created empty with `create_new`, so concurrent exports (even
+/// from different processes) can never end up writing the same path.
+pub fn reserve(dir: &Path, prefix: &str, ext: &str) -> io::Result<ExportFile> {
+    std::fs::create_dir_all(dir)?;
+
+    loop {
+        let name = format!(
+            "{}_{}_{:06x}.{}",
+            prefix,
+            chrono::Utc::now().timestamp(),
+            rand::thread_rng().gen_range(0..0x100_0000),
+            ext
+        );
+        let candidate = dir.join(name);
+
+        match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(_) => {
+                let path = candidate.canonicalize()?;
+                ACTIVE.lock().unwrap().insert(path.clone());
+                return Ok(ExportFile { path });
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether `path` is an export this process is still writing.
+pub fn is_active(path: &Path) -> bool {
+    match path.canonicalize() {
+        Ok(path) => ACTIVE.lock().unwrap().contains(&path),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_names_are_timestamped_and_unique() {
+        let dir = std::env::temp_dir().join(format!("output-{}", std::process::id()));
+        let before = chrono::Utc::now().timestamp();
+        let first = reserve(&dir, "daily", "xlsx").unwrap();
+        let second = reserve(&dir, "daily", "xlsx").unwrap();
+        let after = chrono::Utc::now().timestamp();
+
+        assert_ne!(first.path(), second.path());
+        for file in [&first, &second] {
+            let name = file.path().file_name().unwrap().to_str().unwrap();
+            let stem = name
+                .strip_prefix("daily_")
+                .unwrap()
+                .strip_suffix(".xlsx")
+                .unwrap();
+            let (timestamp, random) = stem.split_once('_').unwrap();
+            assert!(
+                (before..=after).contains(&timestamp.parse().unwrap()),
+                "{}",
+                name
+            );
+            assert_eq!(random.len(), 6, "{}", name);
+            assert!(random.chars().all(|c| c.is_ascii_hexdigit()), "{}", name);
+            assert!(is_active(file.path()));
+        }
+
+        let path = first.path().to_path_buf();
+        drop(first);
+        assert!(!is_active(&path));
+        drop(second);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
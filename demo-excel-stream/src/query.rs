@@ -0,0 +1,332 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Row;
+
+/// A column of the `orders` table that can be exported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderColumn {
+    Id,
+    OrderNumber,
+    CustomerId,
+    CustomerName,
+    CustomerEmail,
+    OrderDate,
+    Status,
+    TotalAmount,
+    ShippingAddress,
+    City,
+    State,
+    Country,
+    PostalCode,
+    PaymentMethod,
+    PaymentStatus,
+    ShippingMethod,
+    TrackingNumber,
+    Notes,
+    CreatedAt,
+}
+
+impl OrderColumn {
+    pub const ALL: [OrderColumn; 19] = [
+        OrderColumn::Id,
+        OrderColumn::OrderNumber,
+        OrderColumn::CustomerId,
+        OrderColumn::CustomerName,
+        OrderColumn::CustomerEmail,
+        OrderColumn::OrderDate,
+        OrderColumn::Status,
+        OrderColumn::TotalAmount,
+        OrderColumn::ShippingAddress,
+        OrderColumn::City,
+        OrderColumn::State,
+        OrderColumn::Country,
+        OrderColumn::PostalCode,
+        OrderColumn::PaymentMethod,
+        OrderColumn::PaymentStatus,
+        OrderColumn::ShippingMethod,
+        OrderColumn::TrackingNumber,
+        OrderColumn::Notes,
+        OrderColumn::CreatedAt,
+    ];
+
+    pub fn sql_name(&self) -> &'static str {
+        match self {
+            OrderColumn::Id => "id",
+            OrderColumn::OrderNumber => "order_number",
+            OrderColumn::CustomerId => "customer_id",
+            OrderColumn::CustomerName => "customer_name",
+            OrderColumn::CustomerEmail => "customer_email",
+            OrderColumn::OrderDate => "order_date",
+            OrderColumn::Status => "status",
+            OrderColumn::TotalAmount => "total_amount",
+            OrderColumn::ShippingAddress => "shipping_address",
+            OrderColumn::City => "city",
+            OrderColumn::State => "state",
+            OrderColumn::Country => "country",
+            OrderColumn::PostalCode => "postal_code",
+            OrderColumn::PaymentMethod => "payment_method",
+            OrderColumn::PaymentStatus => "payment_status",
+            OrderColumn::ShippingMethod => "shipping_method",
+            OrderColumn::TrackingNumber => "tracking_number",
+            OrderColumn::Notes => "notes",
+            OrderColumn::CreatedAt => "created_at",
+        }
+    }
+
+    pub fn header(&self) -> &'static str {
+        match self {
+            OrderColumn::Id => "ID",
+            OrderColumn::OrderNumber => "Order Number",
+            OrderColumn::CustomerId => "Customer ID",
+            OrderColumn::CustomerName => "Customer Name",
+            OrderColumn::CustomerEmail => "Customer Email",
+            OrderColumn::OrderDate => "Order Date",
+            OrderColumn::Status => "Status",
+            OrderColumn::TotalAmount => "Total Amount",
+            OrderColumn::ShippingAddress => "Shipping Address",
+            OrderColumn::City => "City",
+            OrderColumn::State => "State",
+            OrderColumn::Country => "Country",
+            OrderColumn::PostalCode => "Postal Code",
+            OrderColumn::PaymentMethod => "Payment Method",
+            OrderColumn::PaymentStatus => "Payment Status",
+            OrderColumn::ShippingMethod => "Shipping Method",
+            OrderColumn::TrackingNumber => "Tracking Number",
+            OrderColumn::Notes => "Notes",
+            OrderColumn::CreatedAt => "Created At",
+        }
+    }
+
+    pub fn from_sql_name(name: &str) -> Option<OrderColumn> {
+        OrderColumn::ALL.into_iter().find(|c| c.sql_name() == name)
+    }
+}
+
+/// Header label for a result column: the friendly `orders` header when the
+/// name matches a known column, otherwise the raw column name.
+pub fn column_header(name: &str) -> String {
+    OrderColumn::from_sql_name(name)
+        .map(|c| c.header().to_string())
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// Row filters for an `orders` export. Empty lists and `None` mean "no filter".
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OrderFilter {
+    pub status: Vec<String>,
+    pub country: Vec<String>,
+    pub payment_method: Vec<String>,
+    pub payment_status: Vec<String>,
+    pub order_date_from: Option<NaiveDate>,
+    pub order_date_to: Option<NaiveDate>,
+    pub min_total: Option<f64>,
+    pub max_total: Option<f64>,
+}
+
+impl OrderFilter {
+    pub fn is_empty(&self) -> bool {
+        self.status.is_empty()
+            && self.country.is_empty()
+            && self.payment_method.is_empty()
+            && self.payment_status.is_empty()
+            && self.order_date_from.is_none()
+            && self.order_date_to.is_none()
+            && self.min_total.is_none()
+            && self.max_total.is_none()
+    }
+}
+
+/// A parameterised SELECT that an exporter pages through: `orders` queries
+/// by `id` (keyset), custom SQL with LIMIT/OFFSET.
+pub struct ExportQuery {
+    base: String,
+    params: Vec<Box<dyn ToSql + Sync + Send>>,
+    keyset: Option<Keyset>,
+}
+
+/// Keyset paging of an `orders` query.
+struct Keyset {
+    /// `base` with an extra `id > $n` condition for the last id seen.
+    sql: String,
+    /// Position of `id` in the select list.
+    column: usize,
+    /// `id` was added for paging only and is not part of the export.
+    hidden: bool,
+}
+
+/// How far an export has read, so the next page, or a retry of a failed one,
+/// starts in the right place.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PagePosition {
+    /// Rows read so far; the OFFSET of queries without a key.
+    pub rows: usize,
+    /// `id` of the last row read, for keyset-paged queries.
+    pub last_id: Option<i64>,
+}
+
+impl ExportQuery {
+    /// Select `columns` (all of them when empty) from `orders`, ordered by `id`.
+    pub fn orders(columns: &[OrderColumn], filter: &OrderFilter) -> Self {
+        ExportQuery::orders_after(columns, filter, None)
+    }
+
+    /// Like [`ExportQuery::orders`], continuing after the row with `id`
+    /// `after_id` (keyset pagination), so an interrupted export can resume.
+    pub fn orders_after(
+        columns: &[OrderColumn],
+        filter: &OrderFilter,
+        after_id: Option<i64>,
+    ) -> Self {
+        let columns = if columns.is_empty() {
+            &OrderColumn::ALL[..]
+        } else {
+            columns
+        };
+        let mut select_list = columns
+            .iter()
+            .map(|c| c.sql_name())
+            .collect::<Vec<_>>()
+            .join(", ");
+        // Keyset paging needs the id of each row, asked for or not
+        let (key_column, key_hidden) = match columns.iter().position(|c| *c == OrderColumn::Id) {
+            Some(idx) => (idx, false),
+            None => {
+                select_list.push_str(", id");
+                (columns.len(), true)
+            }
+        };
+
+        let mut conditions = Vec::new();
+        let mut params: Vec<Box<dyn ToSql + Sync + Send>> = Vec::new();
+        let mut push = |condition: &str, param: Box<dyn ToSql + Sync + Send>| {
+            params.push(param);
+            conditions.push(condition.replace('?', &format!("${}", params.len())));
+        };
+
+        if !filter.status.is_empty() {
+            push("status = ANY(?)", Box::new(filter.status.clone()));
+        }
+        if !filter.country.is_empty() {
+            push("country = ANY(?)", Box::new(filter.country.clone()));
+        }
+        if !filter.payment_method.is_empty() {
+            push("payment_method = ANY(?)", Box::new(filter.payment_method.clone()));
+        }
+        if !filter.payment_status.is_empty() {
+            push("payment_status = ANY(?)", Box::new(filter.payment_status.clone()));
+        }
+        if let Some(from) = filter.order_date_from {
+            push("order_date >= ?", Box::new(from));
+        }
+        if let Some(to) = filter.order_date_to {
+            push("order_date <= ?", Box::new(to));
+        }
+        if let Some(min) = filter.min_total {
+            push("total_amount >= ?::float8", Box::new(min));
+        }
+        if let Some(max) = filter.max_total {
+            push("total_amount <= ?::float8", Box::new(max));
+        }
+        if let Some(after) = after_id {
+            push("id > ?::int8", Box::new(after));
+        }
+
+        let where_clause = |conditions: &[String]| {
+            if conditions.is_empty() {
+                String::new()
+            } else {
+                format!(" WHERE {}", conditions.join(" AND "))
+            }
+        };
+        let select = |conditions: &[String]| {
+            format!(
+                "SELECT {} FROM orders{} ORDER BY id",
+                select_list,
+                where_clause(conditions)
+            )
+        };
+        let base = select(&conditions);
+        conditions.push(format!("id > ${}::int8", params.len() + 1));
+
+        ExportQuery {
+            base,
+            params,
+            keyset: Some(Keyset {
+                sql: select(&conditions),
+                column: key_column,
+                hidden: key_hidden,
+            }),
+        }
+    }
+
+    /// Wrap an arbitrary SELECT. It should carry its own ORDER BY so that
+    /// paging returns rows in a stable order.
+    pub fn custom(sql: &str) -> Self {
+        ExportQuery {
+            base: format!(
+                "SELECT * FROM ({}) AS export_query",
+                sql.trim().trim_end_matches(';')
+            ),
+            params: Vec::new(),
+            keyset: None,
+        }
+    }
+
+    pub fn sql(&self) -> &str {
+        &self.base
+    }
+
+    pub fn paged_sql(&self, limit: usize, offset: usize) -> String {
+        format!("{} LIMIT {} OFFSET {}", self.base, limit, offset)
+    }
+
+    pub fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.params
+            .iter()
+            .map(|p| p.as_ref() as &(dyn ToSql + Sync))
+            .collect()
+    }
+
+    /// The next `limit` rows after `position`. Run it with
+    /// [`ExportQuery::page_params`].
+    pub fn page_sql(&self, limit: usize, position: &PagePosition) -> String {
+        match (&self.keyset, position.last_id) {
+            (Some(keyset), Some(_)) => format!("{} LIMIT {}", keyset.sql, limit),
+            (Some(_), None) => format!("{} LIMIT {}", self.base, limit),
+            (None, _) => self.paged_sql(limit, position.rows),
+        }
+    }
+
+    pub fn page_params<'a>(&'a self, position: &'a PagePosition) -> Vec<&'a (dyn ToSql + Sync)> {
+        let mut params = self.params();
+        if let (Some(_), Some(last_id)) = (&self.keyset, &position.last_id) {
+            params.push(last_id);
+        }
+        params
+    }
+
+    /// Move `position` past `row`, a row of this query.
+    pub fn advance(&self, position: &mut PagePosition, row: &Row) {
+        position.rows += 1;
+        if let Some(keyset) = &self.keyset {
+            position.last_id = Some(i64::from(row.get::<_, i32>(keyset.column)));
+        }
+    }
+
+    /// A column selected only for paging, to leave out of the output.
+    pub fn hidden_column(&self) -> Option<&'static str> {
+        self.keyset
+            .as_ref()
+            .filter(|k| k.hidden)
+            .map(|_| OrderColumn::Id.sql_name())
+    }
+}
+
+impl Default for ExportQuery {
+    fn default() -> Self {
+        ExportQuery::orders(&OrderColumn::ALL, &OrderFilter::default())
+    }
+}
@@ -0,0 +1,389 @@
+//! Redaction of personal data in exported columns.
+//!
+//! A policy maps column names to a rule: drop the column, mask it
+//! (`j***@example.com`), replace it with a keyed HMAC so exports can still be
+//! joined on it, or truncate it (postal codes to their outward part). Policies
+//! come from `REDACTION_FILE`, with a default and optional per-role overrides;
+//! a single export can tighten its policy further but never loosen it.
+
+use crate::error::AppError;
+use crate::row::CellData;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Characters kept by `truncate` without an explicit length.
+const DEFAULT_TRUNCATE: usize = 3;
+/// Hex characters of the HMAC kept by `hash`.
+const HASH_LEN: usize = 16;
+
+/// What happens to one column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Redaction {
+    Keep,
+    /// Keep the first N characters.
+    Truncate(usize),
+    /// Keep the first character (of the local part, for emails).
+    Mask,
+    /// Keyed HMAC-SHA256, hex, so equal values stay equal.
+    Hash,
+    /// Leave the column out of the export.
+    Drop,
+}
+
+impl Redaction {
+    /// Order used to tighten policies; higher hides more.
+    fn strictness(&self) -> (u8, usize) {
+        match self {
+            Redaction::Keep => (0, 0),
+            Redaction::Truncate(n) => (1, usize::MAX - n),
+            Redaction::Mask => (2, 0),
+            Redaction::Hash => (3, 0),
+            Redaction::Drop => (4, 0),
+        }
+    }
+
+    fn stricter(self, other: Redaction) -> Redaction {
+        if other.strictness() > self.strictness() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl FromStr for Redaction {
+    type Err = String;
+
+    /// `keep`, `drop`, `mask`, `hash`, `truncate` or `truncate:N`.
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "keep" => Ok(Redaction::Keep),
+            "drop" => Ok(Redaction::Drop),
+            "mask" => Ok(Redaction::Mask),
+            "hash" => Ok(Redaction::Hash),
+            "truncate" => Ok(Redaction::Truncate(DEFAULT_TRUNCATE)),
+            other => other
+                .strip_prefix("truncate:")
+                .and_then(|n| n.parse::<usize>().ok())
+                .map(Redaction::Truncate)
+                .ok_or_else(|| format!("unknown redaction '{}'", other)),
+        }
+    }
+}
+
+impl TryFrom<String> for Redaction {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, String> {
+        value.parse()
+    }
+}
+
+impl From<Redaction> for String {
+    fn from(value: Redaction) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for Redaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Redaction::Keep => write!(f, "keep"),
+            Redaction::Truncate(n) => write!(f, "truncate:{}", n),
+            Redaction::Mask => write!(f, "mask"),
+            Redaction::Hash => write!(f, "hash"),
+            Redaction::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Rules by column name. Columns without a rule are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RedactionPolicy {
+    pub columns: BTreeMap<String, Redaction>,
+}
+
+impl RedactionPolicy {
+    /// Parse a comma-separated list such as
+    /// `customer_email:mask,postal_code:truncate:3`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut columns = BTreeMap::new();
+        for part in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (column, rule) = part
+                .split_once(':')
+                .ok_or_else(|| format!("expected column:rule, got '{}'", part))?;
+            columns.insert(column.trim().to_string(), rule.parse()?);
+        }
+        Ok(RedactionPolicy { columns })
+    }
+
+    pub fn rule(&self, column: &str) -> Redaction {
+        self.columns.get(column).copied().unwrap_or(Redaction::Keep)
+    }
+
+    fn uses_hash(&self) -> bool {
+        self.columns.values().any(|r| *r == Redaction::Hash)
+    }
+
+    /// Each column gets the stricter of the two rules.
+    pub fn tighten(&self, other: &RedactionPolicy) -> RedactionPolicy {
+        let mut columns = self.columns.clone();
+        for (column, rule) in &other.columns {
+            let current = columns.get(column).copied().unwrap_or(Redaction::Keep);
+            columns.insert(column.clone(), current.stricter(*rule));
+        }
+        RedactionPolicy { columns }
+    }
+
+    /// Rules from `overrides` replace this policy's, in either direction.
+    fn overlay(&self, overrides: &RedactionPolicy) -> RedactionPolicy {
+        let mut columns = self.columns.clone();
+        columns.extend(overrides.columns.iter().map(|(c, r)| (c.clone(), *r)));
+        RedactionPolicy { columns }
+    }
+}
+
+/// The policies of a deployment, loaded from `REDACTION_FILE`:
+///
+/// ```toml
+/// [default]
+/// customer_email = "mask"
+/// postal_code = "truncate"
+///
+/// [roles.finance]
+/// customer_email = "hash"
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedactionConfig {
+    /// Applies to every export, and to roles without their own entry.
+    pub default: RedactionPolicy,
+    /// Per-role rules, layered over `default` (and able to relax it).
+    pub roles: BTreeMap<String, RedactionPolicy>,
+}
+
+impl RedactionConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+
+    /// The policy of `role`; unknown roles and `None` get the default.
+    pub fn policy_for(&self, role: Option<&str>) -> RedactionPolicy {
+        match role.and_then(|r| self.roles.get(r)) {
+            Some(overrides) => self.default.overlay(overrides),
+            None => self.default.clone(),
+        }
+    }
+
+    pub fn uses_hash(&self) -> bool {
+        self.default.uses_hash() || self.roles.values().any(RedactionPolicy::uses_hash)
+    }
+}
+
+/// A policy resolved against the result columns of one export.
+pub struct Redactor {
+    rules: Vec<Redaction>,
+    hash_key: Option<Vec<u8>>,
+}
+
+impl Redactor {
+    /// `hash_key` is required when any column is hashed.
+    pub fn new<S: AsRef<str>>(
+        columns: &[S],
+        policy: &RedactionPolicy,
+        hash_key: Option<&str>,
+    ) -> Result<Self, AppError> {
+        let rules: Vec<Redaction> = columns.iter().map(|c| policy.rule(c.as_ref())).collect();
+        if hash_key.is_none() && rules.contains(&Redaction::Hash) {
+            return Err(AppError::BadRequest(
+                "hashing columns requires REDACTION_HASH_KEY".to_string(),
+            ));
+        }
+        Ok(Redactor {
+            rules,
+            hash_key: hash_key.map(|k| k.as_bytes().to_vec()),
+        })
+    }
+
+    /// The columns that remain after dropping, in order.
+    pub fn kept<T: Clone>(&self, columns: &[T]) -> Vec<T> {
+        columns
+            .iter()
+            .zip(&self.rules)
+            .filter(|(_, rule)| **rule != Redaction::Drop)
+            .map(|(c, _)| c.clone())
+            .collect()
+    }
+
+    /// Rule for result column `idx`.
+    pub fn rule(&self, idx: usize) -> Redaction {
+        self.rules.get(idx).copied().unwrap_or(Redaction::Keep)
+    }
+
+    /// Apply `rule` to one cell. Empty cells stay empty.
+    pub fn apply(&self, rule: Redaction, cell: CellData) -> CellData {
+        if cell == CellData::Empty {
+            return cell;
+        }
+        match rule {
+            Redaction::Keep | Redaction::Drop => cell,
+            Redaction::Truncate(n) => CellData::Text(cell.to_text().chars().take(n).collect()),
+            Redaction::Mask => CellData::Text(mask(&cell.to_text())),
+            Redaction::Hash => CellData::Text(self.hash(&cell.to_text())),
+        }
+    }
+
+    fn hash(&self, value: &str) -> String {
+        let key = self.hash_key.as_deref().unwrap_or_default();
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(value.as_bytes());
+        let mut digest = hex::encode(mac.finalize().into_bytes());
+        digest.truncate(HASH_LEN);
+        digest
+    }
+}
+
+/// `john.doe@example.com` -> `j***@example.com`, `Jane Smith` -> `J***`.
+fn mask(value: &str) -> String {
+    let (local, domain) = match value.split_once('@') {
+        Some((local, domain)) => (local, Some(domain)),
+        None => (value, None),
+    };
+    let first: String = local.chars().take(1).collect();
+    match domain {
+        Some(domain) => format!("{}***@{}", first, domain),
+        None => format!("{}***", first),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(value: &str) -> RedactionPolicy {
+        RedactionPolicy::parse(value).unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        let p = policy("customer_email:mask, postal_code:truncate:2,notes:drop,name:truncate");
+        assert_eq!(p.rule("customer_email"), Redaction::Mask);
+        assert_eq!(p.rule("postal_code"), Redaction::Truncate(2));
+        assert_eq!(p.rule("name"), Redaction::Truncate(DEFAULT_TRUNCATE));
+        assert_eq!(p.rule("notes"), Redaction::Drop);
+        assert_eq!(p.rule("order_id"), Redaction::Keep);
+
+        assert!(RedactionPolicy::parse("customer_email").is_err());
+        assert!(RedactionPolicy::parse("customer_email:blur").is_err());
+        assert!(RedactionPolicy::parse("postal_code:truncate:x").is_err());
+    }
+
+    #[test]
+    fn tightening_never_loosens() {
+        let base = policy("customer_email:hash,postal_code:truncate:3,name:mask");
+        let request = policy("customer_email:keep,postal_code:truncate:1,name:drop,notes:mask");
+        let tightened = base.tighten(&request);
+        assert_eq!(tightened.rule("customer_email"), Redaction::Hash);
+        assert_eq!(tightened.rule("postal_code"), Redaction::Truncate(1));
+        assert_eq!(tightened.rule("name"), Redaction::Drop);
+        assert_eq!(tightened.rule("notes"), Redaction::Mask);
+
+        // a longer truncation keeps more, so it does not replace a shorter one
+        let looser = policy("postal_code:truncate:5");
+        assert_eq!(
+            tightened.tighten(&looser).rule("postal_code"),
+            Redaction::Truncate(1)
+        );
+    }
+
+    #[test]
+    fn role_overrides_replace_the_default() {
+        let config: RedactionConfig = toml::from_str(
+            "[default]\ncustomer_email = \"mask\"\npostal_code = \"truncate\"\n\
+             [roles.finance]\ncustomer_email = \"hash\"\n\
+             [roles.support]\ncustomer_email = \"keep\"\n",
+        )
+        .unwrap();
+        assert!(config.uses_hash());
+        assert_eq!(
+            config.policy_for(Some("finance")).rule("customer_email"),
+            Redaction::Hash
+        );
+        assert_eq!(
+            config.policy_for(Some("support")).rule("customer_email"),
+            Redaction::Keep
+        );
+        assert_eq!(
+            config.policy_for(Some("support")).rule("postal_code"),
+            Redaction::Truncate(3)
+        );
+        assert_eq!(config.policy_for(Some("unknown")), config.default);
+        assert_eq!(config.policy_for(None), config.default);
+    }
+
+    #[test]
+    fn applies_rules_to_cells() {
+        let columns = ["id", "customer_email", "postal_code", "notes", "name"];
+        let p = policy("customer_email:hash,postal_code:truncate:3,notes:drop,name:mask");
+        let redactor = Redactor::new(&columns, &p, Some("key")).unwrap();
+        assert_eq!(
+            redactor.kept(&columns),
+            vec!["id", "customer_email", "postal_code", "name"]
+        );
+
+        let text = |s: &str| CellData::Text(s.to_string());
+        assert_eq!(
+            redactor.apply(redactor.rule(0), CellData::Int(7)),
+            CellData::Int(7)
+        );
+        assert_eq!(
+            redactor.apply(redactor.rule(2), text("SW1A 1AA")),
+            text("SW1")
+        );
+        assert_eq!(
+            redactor.apply(redactor.rule(4), text("Jane Smith")),
+            text("J***")
+        );
+        assert_eq!(
+            redactor.apply(redactor.rule(4), CellData::Empty),
+            CellData::Empty
+        );
+
+        let hashed = redactor.apply(redactor.rule(1), text("jane@example.com"));
+        assert_eq!(
+            hashed,
+            redactor.apply(Redaction::Hash, text("jane@example.com"))
+        );
+        assert_ne!(
+            hashed,
+            redactor.apply(Redaction::Hash, text("john@example.com"))
+        );
+        match hashed {
+            CellData::Text(digest) => assert_eq!(digest.len(), HASH_LEN),
+            other => panic!("expected text, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hashing_requires_a_key() {
+        let p = policy("customer_email:hash");
+        assert!(Redactor::new(&["customer_email"], &p, None).is_err());
+        assert!(Redactor::new(&["postal_code"], &p, None).is_ok());
+    }
+
+    #[test]
+    fn masks_emails_and_names() {
+        assert_eq!(mask("john.doe@example.com"), "j***@example.com");
+        assert_eq!(mask("Émile"), "É***");
+        assert_eq!(mask("x"), "x***");
+    }
+}
@@ -0,0 +1,351 @@
+use crate::config::Config;
+use crate::db::DbPool;
+use crate::error::AppError;
+use crate::export::{export_query, ExportFormat, ExportSpec, ExportedFile};
+use crate::output;
+use crate::query::{ExportQuery, OrderColumn, OrderFilter};
+use crate::redaction::RedactionPolicy;
+use crate::style::StyleOptions;
+use crate::summary::Dimension;
+use crate::template;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A named, saved export definition.
+///
+/// Loaded from `*.toml` / `*.json` files in `REPORTS_DIR` or from the
+/// `reports` table. A report either selects `columns` from `orders` narrowed
+/// by `filters`, or runs its own `sql`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportDefinition {
+    /// Defaults to the file stem when loaded from a file.
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub sql: Option<String>,
+    #[serde(default)]
+    pub filters: OrderFilter,
+    /// Empty means all `orders` columns.
+    #[serde(default)]
+    pub columns: Vec<OrderColumn>,
+    #[serde(default = "default_formats")]
+    pub formats: Vec<ExportFormat>,
+    #[serde(default = "default_sheet_name")]
+    pub sheet_name: String,
+    /// Sheet styling; `EXPORT_STYLE` applies when omitted.
+    #[serde(default)]
+    pub style: Option<StyleOptions>,
+    /// Add summary sheets to xlsx output; `EXPORT_SUMMARY` applies when omitted.
+    #[serde(default)]
+    pub summary: Option<bool>,
+    /// Add charts to the summary sheets; `EXPORT_CHARTS` applies when omitted.
+    #[serde(default)]
+    pub charts: Option<bool>,
+    /// Template in `TEMPLATES_DIR` to fill for xlsx output; `EXPORT_TEMPLATE`
+    /// applies when omitted.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Defined name or `{{marker}}` text locating the data in the template.
+    #[serde(default)]
+    pub template_anchor: Option<String>,
+    /// Environment variable holding the password to encrypt xlsx output with;
+    /// `EXPORT_PASSWORD` applies when omitted. Reports with a password cannot
+    /// include csv.
+    #[serde(default)]
+    pub password_env: Option<String>,
+    /// Environment variable holding the sheet protection password (may be
+    /// empty); `EXPORT_SHEET_PASSWORD` applies when omitted.
+    #[serde(default)]
+    pub sheet_password_env: Option<String>,
+    /// Role whose redaction policy applies; the default policy when omitted.
+    #[serde(default)]
+    pub role: Option<String>,
+    /// Extra per-column redaction, e.g. `customer_email = "mask"`. It can
+    /// only tighten the role's policy.
+    #[serde(default)]
+    pub redact: RedactionPolicy,
+    /// Cron expression (UTC) for the server's scheduler, e.g. `"0 2 * * *"`.
+    #[serde(default)]
+    pub schedule: Option<String>,
+}
+
+fn default_formats() -> Vec<ExportFormat> {
+    vec![ExportFormat::Xlsx]
+}
+
+fn default_sheet_name() -> String {
+    "Orders".to_string()
+}
+
+impl ReportDefinition {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!(
+                "invalid report name '{}': use letters, digits, '_' or '-'",
+                self.name
+            ));
+        }
+        if self.sql.is_some() && (!self.columns.is_empty() || !self.filters.is_empty()) {
+            return Err(format!(
+                "report '{}': `sql` cannot be combined with `columns` or `filters`",
+                self.name
+            ));
+        }
+        if self.formats.is_empty() {
+            return Err(format!("report '{}': `formats` must not be empty", self.name));
+        }
+        if self.sheet_name.is_empty() || self.sheet_name.chars().count() > 31 {
+            return Err(format!(
+                "report '{}': sheet name must be 1-31 characters",
+                self.name
+            ));
+        }
+        // Checked even when summaries are off here: `EXPORT_SUMMARY` may turn
+        // them on.
+        if Dimension::is_summary_sheet_name(&self.sheet_name) {
+            return Err(format!(
+                "report '{}': sheet name '{}' is taken by a summary sheet",
+                self.name, self.sheet_name
+            ));
+        }
+        if self.password_env.is_some() && self.formats.contains(&ExportFormat::Csv) {
+            return Err(format!(
+                "report '{}': csv output cannot be password-protected",
+                self.name
+            ));
+        }
+        if let Some(expr) = &self.schedule {
+            parse_schedule(expr).map_err(|e| format!("report '{}': {}", self.name, e))?;
+        }
+        Ok(())
+    }
+
+    pub fn query(&self) -> ExportQuery {
+        match &self.sql {
+            Some(sql) => ExportQuery::custom(sql),
+            None => ExportQuery::orders(&self.columns, &self.filters),
+        }
+    }
+}
+
+/// Parse a cron expression. Standard 5-field expressions
+/// (`min hour day month weekday`) are accepted as well as the 6/7-field form
+/// with seconds (and years) understood by the `cron` crate.
+pub fn parse_schedule(expr: &str) -> Result<cron::Schedule, String> {
+    let expr = expr.trim();
+    let full = if expr.split_whitespace().count() == 5 {
+        format!("0 {}", expr)
+    } else {
+        expr.to_string()
+    };
+    full.parse::<cron::Schedule>()
+        .map_err(|e| format!("invalid schedule '{}': {}", expr, e))
+}
+
+/// All report definitions known to the application, keyed by name.
+#[derive(Debug, Default)]
+pub struct ReportCatalog {
+    reports: BTreeMap<String, ReportDefinition>,
+    invalid: Vec<InvalidReport>,
+}
+
+/// A report file or `reports` row that was skipped, and why.
+#[derive(Debug, Clone, Serialize)]
+pub struct InvalidReport {
+    /// The file path, or `reports:<name>` for a table row.
+    pub source: String,
+    pub error: String,
+}
+
+impl InvalidReport {
+    fn new(source: String, error: String) -> Self {
+        tracing::warn!(%source, %error, "skipping invalid report definition");
+        InvalidReport { source, error }
+    }
+}
+
+impl ReportCatalog {
+    /// Load reports from `config.reports_dir` and the `reports` table.
+    /// A table entry overrides a file with the same name. Invalid
+    /// definitions are skipped and listed by [`ReportCatalog::invalid`].
+    pub async fn load(pool: &DbPool, config: &Config) -> Result<Self, AppError> {
+        let mut catalog = ReportCatalog::default();
+        for report in load_dir(Path::new(&config.reports_dir))? {
+            catalog.add(report);
+        }
+        for report in load_table(pool).await? {
+            catalog.add(report);
+        }
+        Ok(catalog)
+    }
+
+    fn add(&mut self, report: Result<ReportDefinition, InvalidReport>) {
+        match report {
+            Ok(report) => self.insert(report),
+            Err(invalid) => self.invalid.push(invalid),
+        }
+    }
+
+    pub fn insert(&mut self, report: ReportDefinition) {
+        self.reports.insert(report.name.clone(), report);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ReportDefinition> {
+        self.reports.get(name)
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &ReportDefinition> {
+        self.reports.values()
+    }
+
+    /// Definitions that failed to parse or validate.
+    pub fn invalid(&self) -> &[InvalidReport] {
+        &self.invalid
+    }
+}
+
+/// Parse every `*.toml` and `*.json` file in `dir`. A missing directory
+/// yields no reports; a file that cannot be read or parsed yields an
+/// [`InvalidReport`] without affecting the others.
+pub fn load_dir(dir: &Path) -> Result<Vec<Result<ReportDefinition, InvalidReport>>, AppError> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut reports = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        if ext != "toml" && ext != "json" {
+            continue;
+        }
+        let report = parse_file(&path, ext)
+            .map_err(|e| InvalidReport::new(path.display().to_string(), e));
+        reports.push(report);
+    }
+
+    Ok(reports)
+}
+
+fn parse_file(path: &Path, ext: &str) -> Result<ReportDefinition, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut report: ReportDefinition = if ext == "toml" {
+        toml::from_str(&content).map_err(|e| e.to_string())?
+    } else {
+        serde_json::from_str(&content).map_err(|e| e.to_string())?
+    };
+
+    if report.name.is_empty() {
+        report.name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+    }
+    report.validate()?;
+    Ok(report)
+}
+
+/// Read definitions stored as JSON in the `reports` table, if it exists.
+async fn load_table(
+    pool: &DbPool,
+) -> Result<Vec<Result<ReportDefinition, InvalidReport>>, AppError> {
+    let client = pool.get_client().await?;
+
+    let exists: bool = client
+        .query_one("SELECT to_regclass('reports') IS NOT NULL", &[])
+        .await?
+        .get(0);
+    if !exists {
+        return Ok(Vec::new());
+    }
+
+    let rows = client
+        .query("SELECT name, definition::text FROM reports ORDER BY name", &[])
+        .await?;
+
+    let mut reports = Vec::with_capacity(rows.len());
+    for row in rows {
+        let name: String = row.get(0);
+        let definition: String = row.get(1);
+        let report = serde_json::from_str::<ReportDefinition>(&definition)
+            .map_err(|e| e.to_string())
+            .and_then(|mut report| {
+                report.name = name.clone();
+                report.validate()?;
+                Ok(report)
+            })
+            .map_err(|e| InvalidReport::new(format!("reports:{}", name), e));
+        reports.push(report);
+    }
+
+    Ok(reports)
+}
+
+/// Run `report` once per configured format, writing uniquely named
+/// `{name}_{timestamp}_{suffix}.{ext}` files into `output_dir`.
+pub async fn run_report(
+    pool: Arc<DbPool>,
+    config: &Config,
+    report: &ReportDefinition,
+    output_dir: &Path,
+) -> Result<Vec<ExportedFile>, AppError> {
+    let mut files = Vec::with_capacity(report.formats.len());
+    let password = match &report.password_env {
+        Some(name) => Some(secret_env(name)?),
+        None => config.export_password.clone(),
+    };
+    let sheet_password = match &report.sheet_password_env {
+        Some(name) => Some(secret_env(name)?),
+        None => config.export_sheet_password.clone(),
+    };
+
+    for format in &report.formats {
+        let template = match (
+            format,
+            report.template.as_ref().or(config.export_template.as_ref()),
+        ) {
+            (ExportFormat::Xlsx, Some(name)) => Some(template::resolve(
+                Path::new(&config.templates_dir),
+                name,
+                report
+                    .template_anchor
+                    .as_deref()
+                    .or(config.export_template_anchor.as_deref()),
+            )?),
+            _ => None,
+        };
+        let spec = ExportSpec {
+            query: report.query(),
+            sheet_name: report.sheet_name.clone(),
+            format: *format,
+            style: report.style.unwrap_or(config.export_style),
+            summary: report.summary.unwrap_or(config.export_summary),
+            charts: report.charts.unwrap_or(config.export_charts),
+            template,
+            password: password.clone(),
+            sheet_password: sheet_password.clone(),
+            role: report.role.clone(),
+            redact: report.redact.clone(),
+        };
+        let file = output::reserve(output_dir, &report.name, format.extension())?;
+        files.push(export_query(pool.clone(), config, &spec, Some(file.path().to_path_buf())).await?);
+    }
+
+    Ok(files)
+}
+
+/// Read a password named by a report, so the secret itself stays out of the
+/// report definition.
+fn secret_env(name: &str) -> Result<String, AppError> {
+    std::env::var(name)
+        .map_err(|_| AppError::Config(format!("environment variable {} is not set", name)))
+}
@@ -0,0 +1,178 @@
+//! Request ids and uniform JSON errors for the HTTP API.
+//!
+//! Every request gets an id: the caller's `X-Request-Id` when it is a sane
+//! token, otherwise a fresh random one. It is echoed in the response header,
+//! added to error bodies, and logged with failures so a caller's report can
+//! be matched to the log.
+//!
+//! The middleware also opens the `http_request` span that everything logged
+//! while handling the request belongs to. A W3C `traceparent` header makes
+//! it part of the caller's trace.
+
+use crate::error::{error_body, AppError};
+use crate::telemetry;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use std::future::{ready, Ready};
+use std::time::Instant;
+use tracing::Instrument;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Longest caller-supplied id that is accepted.
+const MAX_LEN: usize = 64;
+
+/// The id of the current request, available as an extractor.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl FromRequest for RequestId {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let id = req
+            .extensions()
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| RequestId(generate()));
+        ready(Ok(id))
+    }
+}
+
+fn generate() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Accept the caller's id only if it cannot garble a log line.
+fn from_header(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let valid = !value.is_empty()
+        && value.len() <= MAX_LEN
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    valid.then(|| value.to_string())
+}
+
+/// Actix middleware: assign the request id and turn every error response,
+/// ours or the framework's (e.g. a malformed query string), into the JSON
+/// body of [`AppError::to_response`]. Wrap it around everything else.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let id = from_header(&req).unwrap_or_else(generate);
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let route = format!("{} {}", req.method(), req.path());
+    let span = tracing::info_span!(
+        "http_request",
+        request_id = %id,
+        http.request.method = %req.method(),
+        url.path = %req.path(),
+        http.route = tracing::field::Empty,
+        http.response.status_code = tracing::field::Empty,
+        otel.kind = "server",
+        otel.status_code = tracing::field::Empty,
+    );
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    if let Some(traceparent) = header("traceparent") {
+        telemetry::set_remote_parent(&span, traceparent, header("tracestate"));
+    }
+    let start = Instant::now();
+
+    let result = next.call(req).instrument(span.clone()).await;
+    let _entered = span.enter();
+    match result {
+        Ok(res) => {
+            if let Some(pattern) = res.request().match_pattern() {
+                span.record("http.route", pattern.as_str());
+            }
+            let rewritten = res
+                .response()
+                .error()
+                .map(|err| error_response(&route, err, &id));
+            let mut res = res.map_into_boxed_body();
+            if let Some(response) = rewritten {
+                res = res.into_response(response);
+            }
+            res.headers_mut().insert(
+                HeaderName::from_static("x-request-id"),
+                request_id_value(&id),
+            );
+            let status = res.status().as_u16();
+            span.record("http.response.status_code", status);
+            tracing::debug!(
+                status,
+                elapsed_ms = start.elapsed().as_millis() as u64,
+                "request finished"
+            );
+            Ok(res)
+        }
+        // Errors from inner middleware (e.g. authentication) arrive without
+        // a response; hand back one that renders as ours.
+        Err(err) => {
+            let response = error_response(&route, &err, &id);
+            span.record("http.response.status_code", response.status().as_u16());
+            Err(InternalError::from_response(err.to_string(), response).into())
+        }
+    }
+}
+
+fn request_id_value(id: &str) -> HeaderValue {
+    HeaderValue::from_str(id).expect("request ids are header-safe")
+}
+
+/// Log `err` under the request id and build its JSON response.
+fn error_response(route: &str, err: &actix_web::Error, id: &str) -> HttpResponse {
+    let mut response = match err.as_error::<AppError>() {
+        Some(app) => app.to_response(Some(id)),
+        None => {
+            let status = err.as_response_error().status_code();
+            let message = if status.is_server_error() {
+                "internal server error".to_string()
+            } else {
+                err.to_string()
+            };
+            HttpResponse::build(status).json(error_body(
+                &message,
+                status_code_name(status),
+                Some(id),
+            ))
+        }
+    };
+    let status = response.status().as_u16();
+    if response.status().is_server_error() {
+        tracing::Span::current().record("otel.status_code", "ERROR");
+        tracing::error!(route, status, error = %err, "request failed");
+    } else {
+        tracing::warn!(route, status, error = %err, "request failed");
+    }
+    response.headers_mut().insert(
+        HeaderName::from_static("x-request-id"),
+        request_id_value(id),
+    );
+    response
+}
+
+/// `code` for errors raised by the framework rather than as an [`AppError`].
+fn status_code_name(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::METHOD_NOT_ALLOWED => "method_not_allowed",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limited",
+        StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+        StatusCode::GATEWAY_TIMEOUT => "timeout",
+        s if s.is_client_error() => "bad_request",
+        _ => "internal",
+    }
+}
@@ -0,0 +1,241 @@
+use crate::output;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Files younger than this are never deleted, even if a limit is exceeded.
+/// Covers exports written by other processes (e.g. the CLI) that this
+/// process cannot see as active.
+const MIN_AGE: Duration = Duration::from_secs(60);
+
+/// File extensions the janitor considers export output.
+const EXPORT_EXTENSIONS: [&str; 2] = ["xlsx", "csv"];
+
+/// Limits on the exports kept in the output directory. `None` disables a limit.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    pub max_total_bytes: Option<u64>,
+    pub max_files: Option<usize>,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_total_bytes.is_some() || self.max_files.is_some()
+    }
+}
+
+/// Result of one cleanup pass.
+#[derive(Debug, Default)]
+pub struct CleanupReport {
+    pub deleted: Vec<PathBuf>,
+    pub freed_bytes: u64,
+}
+
+struct ExportEntry {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Delete exports in `dir` that violate `policy`: first everything older than
+/// `max_age`, then the oldest remaining files until both `max_files` and
+/// `max_total_bytes` are satisfied.
+pub fn apply(dir: &Path, policy: &RetentionPolicy) -> std::io::Result<CleanupReport> {
+    let mut report = CleanupReport::default();
+    if !dir.is_dir() || !policy.is_enabled() {
+        return Ok(report);
+    }
+
+    let now = SystemTime::now();
+    let age = |entry: &ExportEntry| now.duration_since(entry.modified).unwrap_or_default();
+
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let path = entry.path();
+        let is_export = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| EXPORT_EXTENSIONS.contains(&e));
+        if !meta.is_file() || !is_export {
+            continue;
+        }
+        entries.push(ExportEntry {
+            path,
+            size: meta.len(),
+            modified: meta.modified()?,
+        });
+    }
+
+    // Oldest first.
+    entries.sort_by_key(|e| e.modified);
+
+    let mut kept = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let expired = policy.max_age.is_some_and(|max| age(&entry) > max);
+        if expired && deletable(&entry, age(&entry)) {
+            delete(entry, &mut report);
+        } else {
+            kept.push(entry);
+        }
+    }
+
+    let mut total: u64 = kept.iter().map(|e| e.size).sum();
+    let mut count = kept.len();
+    for entry in kept {
+        let over_count = policy.max_files.is_some_and(|max| count > max);
+        let over_size = policy.max_total_bytes.is_some_and(|max| total > max);
+        if !over_count && !over_size {
+            break;
+        }
+        if !deletable(&entry, age(&entry)) {
+            continue;
+        }
+        let size = entry.size;
+        delete(entry, &mut report);
+        total -= size;
+        count -= 1;
+    }
+
+    Ok(report)
+}
+
+fn deletable(entry: &ExportEntry, age: Duration) -> bool {
+    age >= MIN_AGE && !output::is_active(&entry.path)
+}
+
+fn delete(entry: ExportEntry, report: &mut CleanupReport) {
+    match std::fs::remove_file(&entry.path) {
+        Ok(()) => {
+            report.freed_bytes += entry.size;
+            report.deleted.push(entry.path);
+        }
+        Err(e) => tracing::warn!(
+            path = %entry.path.display(),
+            error = %e,
+            "Retention: failed to delete export"
+        ),
+    }
+}
+
+/// Spawn a background task that applies `policy` to `dir` every `interval`.
+pub fn spawn_janitor(dir: PathBuf, policy: RetentionPolicy, interval: Duration) {
+    if !policy.is_enabled() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let dir = dir.clone();
+            let policy = policy.clone();
+            match tokio::task::spawn_blocking(move || apply(&dir, &policy)).await {
+                Ok(Ok(report)) if !report.deleted.is_empty() => tracing::info!(
+                    "Retention: deleted {} export(s), freed {:.2} MB",
+                    report.deleted.len(),
+                    report.freed_bytes as f64 / 1_048_576.0
+                ),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::error!(error = %e, "Retention: cleanup failed"),
+                Err(e) => tracing::error!(error = %e, "Retention: cleanup task panicked"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("retention-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write `name` with `size` bytes, last modified `age` ago.
+    fn file(dir: &Path, name: &str, size: usize, age: Duration) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, vec![0; size]).unwrap();
+        set_age(&path, age);
+        path
+    }
+
+    fn set_age(path: &Path, age: Duration) {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn deletes_exports_older_than_max_age() {
+        let dir = temp_dir("age");
+        let old = file(&dir, "old.xlsx", 10, 3 * HOUR);
+        let old_csv = file(&dir, "old.csv", 5, 2 * HOUR);
+        let recent = file(&dir, "recent.xlsx", 10, HOUR / 2);
+        let other = file(&dir, "notes.txt", 10, 3 * HOUR);
+        let policy = RetentionPolicy {
+            max_age: Some(HOUR),
+            ..Default::default()
+        };
+
+        let report = apply(&dir, &policy).unwrap();
+
+        assert_eq!(report.deleted, vec![old.clone(), old_csv.clone()]);
+        assert_eq!(report.freed_bytes, 15);
+        assert!(!old.exists() && !old_csv.exists());
+        assert!(recent.exists());
+        assert!(other.exists(), "only export files are touched");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn limits_delete_oldest_first_but_spare_young_files() {
+        let dir = temp_dir("min-age");
+        let oldest = file(&dir, "a.xlsx", 10, 3 * HOUR);
+        let older = file(&dir, "b.xlsx", 10, 2 * HOUR);
+        let fresh = file(&dir, "c.xlsx", 10, MIN_AGE / 2);
+        let policy = RetentionPolicy {
+            max_files: Some(0),
+            ..Default::default()
+        };
+
+        let report = apply(&dir, &policy).unwrap();
+
+        assert_eq!(report.deleted, vec![oldest, older]);
+        assert!(fresh.exists(), "files younger than MIN_AGE are kept");
+
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::ZERO),
+            max_total_bytes: Some(0),
+            ..Default::default()
+        };
+        assert!(apply(&dir, &policy).unwrap().deleted.is_empty());
+        assert!(fresh.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn never_deletes_exports_in_progress() {
+        let dir = temp_dir("active");
+        let export = output::reserve(&dir, "orders", "xlsx").unwrap();
+        set_age(export.path(), 3 * HOUR);
+        let policy = RetentionPolicy {
+            max_age: Some(HOUR),
+            max_files: Some(0),
+            max_total_bytes: Some(0),
+        };
+
+        assert!(apply(&dir, &policy).unwrap().deleted.is_empty());
+        assert!(export.path().exists());
+
+        let path = export.path().to_path_buf();
+        drop(export);
+        assert_eq!(apply(&dir, &policy).unwrap().deleted, vec![path]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
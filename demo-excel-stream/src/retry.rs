@@ -0,0 +1,70 @@
+//! Retry policy for transient database failures.
+//!
+//! A dropped connection, a server restart or a serialization failure is
+//! worth another attempt after a pause; a syntax error or a constraint
+//! violation is not, and fails at once.
+
+use rand::Rng;
+use std::time::Duration;
+use tokio_postgres::error::SqlState;
+
+/// How often and how patiently to retry. `max_attempts` counts the first
+/// try, so 1 disables retrying.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Pause before attempt `attempt + 1`: exponential backoff capped at
+    /// `max_delay`, with "equal jitter" (half fixed, half random) so that
+    /// clients failing together do not retry together.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1u32 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        let half = exp / 2;
+        half + half.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// Whether `err` may succeed on another attempt: lost connections,
+/// serialization failures and deadlocks, server shutdowns and restarts, and
+/// too many connections.
+pub fn is_transient(err: &tokio_postgres::Error) -> bool {
+    let Some(state) = err.code() else {
+        // No SQLSTATE: the connection broke (reset, refused, closed).
+        return err.is_closed() || crate::error::source_is_io(err);
+    };
+    *state == SqlState::T_R_SERIALIZATION_FAILURE
+        || *state == SqlState::T_R_DEADLOCK_DETECTED
+        || *state == SqlState::ADMIN_SHUTDOWN
+        || *state == SqlState::CRASH_SHUTDOWN
+        || *state == SqlState::CANNOT_CONNECT_NOW
+        || *state == SqlState::TOO_MANY_CONNECTIONS
+        // Connection exceptions.
+        || state.code().starts_with("08")
+}
+
+/// One-line description for the retry log; the driver's own `Display`
+/// leaves out both the server message and the I/O cause.
+pub fn describe(err: &tokio_postgres::Error) -> String {
+    match (err.as_db_error(), std::error::Error::source(err)) {
+        (Some(db), _) => format!("{} ({})", db.message(), db.code().code()),
+        (None, Some(cause)) => format!("{}: {}", err, cause),
+        (None, None) => err.to_string(),
+    }
+}
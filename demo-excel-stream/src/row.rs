@@ -0,0 +1,106 @@
+use crate::error::AppError;
+use crate::redaction::{Redaction, Redactor};
+use rust_decimal::prelude::ToPrimitive;
+use tokio_postgres::types::{FromSql, Type};
+use tokio_postgres::Row;
+
+/// A backend-neutral cell value produced from a database row.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CellData {
+    Empty,
+    Int(i64),
+    Number(f64),
+    Text(String),
+}
+
+impl CellData {
+    /// Plain-text rendering, used by CSV output.
+    pub fn to_text(&self) -> String {
+        match self {
+            CellData::Empty => String::new(),
+            CellData::Int(v) => v.to_string(),
+            CellData::Number(v) => v.to_string(),
+            CellData::Text(s) => s.clone(),
+        }
+    }
+
+    /// Approximate width in characters when shown in a cell.
+    pub fn display_len(&self) -> usize {
+        match self {
+            CellData::Text(s) => s.chars().count(),
+            other => other.to_text().len(),
+        }
+    }
+}
+
+/// Convert every column of `row` into a `CellData`, based on its Postgres type.
+/// NULLs become `CellData::Empty`; text-like and JSON columns become text.
+/// Other types (uuid, arrays, interval, …) and undecodable values are an
+/// error naming the column, so they are never written as blank cells.
+pub fn map_row(row: &Row) -> Result<Vec<CellData>, AppError> {
+    (0..row.len()).map(|idx| map_cell(row, idx)).collect()
+}
+
+/// Like `map_row`, applying `redactor`'s rule to each column. Dropped columns
+/// are left out (and never decoded).
+pub fn map_row_redacted(row: &Row, redactor: &Redactor) -> Result<Vec<CellData>, AppError> {
+    (0..row.len())
+        .filter_map(|idx| match redactor.rule(idx) {
+            Redaction::Drop => None,
+            rule => Some(map_cell(row, idx).map(|cell| redactor.apply(rule, cell))),
+        })
+        .collect()
+}
+
+fn map_cell(row: &Row, idx: usize) -> Result<CellData, AppError> {
+    let column = &row.columns()[idx];
+    let ty = column.type_();
+
+    // A value that does not decode stays a database error.
+    let cell = if *ty == Type::INT2 {
+        row.try_get::<_, Option<i16>>(idx)?
+            .map(|v| CellData::Int(v as i64))
+    } else if *ty == Type::INT4 {
+        row.try_get::<_, Option<i32>>(idx)?
+            .map(|v| CellData::Int(v as i64))
+    } else if *ty == Type::INT8 {
+        row.try_get::<_, Option<i64>>(idx)?.map(CellData::Int)
+    } else if *ty == Type::FLOAT4 {
+        row.try_get::<_, Option<f32>>(idx)?
+            .map(|v| CellData::Number(v as f64))
+    } else if *ty == Type::FLOAT8 {
+        row.try_get::<_, Option<f64>>(idx)?.map(CellData::Number)
+    } else if *ty == Type::NUMERIC {
+        row.try_get::<_, Option<rust_decimal::Decimal>>(idx)?
+            .map(|v| match v.to_f64() {
+                Some(f) => CellData::Number(f),
+                None => CellData::Text(v.to_string()),
+            })
+    } else if *ty == Type::BOOL {
+        row.try_get::<_, Option<bool>>(idx)?
+            .map(|v| CellData::Text(v.to_string()))
+    } else if *ty == Type::DATE {
+        row.try_get::<_, Option<chrono::NaiveDate>>(idx)?
+            .map(|d| CellData::Text(d.format("%Y-%m-%d").to_string()))
+    } else if *ty == Type::TIMESTAMP {
+        row.try_get::<_, Option<chrono::NaiveDateTime>>(idx)?
+            .map(|t| CellData::Text(t.format("%Y-%m-%d %H:%M:%S").to_string()))
+    } else if *ty == Type::TIMESTAMPTZ {
+        row.try_get::<_, Option<chrono::DateTime<chrono::Utc>>>(idx)?
+            .map(|t| CellData::Text(t.format("%Y-%m-%d %H:%M:%S").to_string()))
+    } else if *ty == Type::JSON || *ty == Type::JSONB {
+        row.try_get::<_, Option<serde_json::Value>>(idx)?
+            .map(|v| CellData::Text(v.to_string()))
+    } else if <String as FromSql>::accepts(ty) {
+        row.try_get::<_, Option<String>>(idx)?.map(CellData::Text)
+    } else {
+        return Err(AppError::Unsupported(format!(
+            "column '{}' has unsupported type {}; cast it in the query, e.g. `{}::text`",
+            column.name(),
+            ty,
+            column.name()
+        )));
+    };
+
+    Ok(cell.unwrap_or(CellData::Empty))
+}
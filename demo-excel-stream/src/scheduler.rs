@@ -0,0 +1,400 @@
+use crate::config::Config;
+use crate::db::DbPool;
+use crate::jobs::{JobKind, JobOutput, JobRequest, Jobs};
+use crate::limits::ExportLimiter;
+use crate::reports::{parse_schedule, run_report, ReportCatalog, ReportDefinition};
+use crate::storage::{self, ExportStorage};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::Instrument;
+
+/// Number of runs kept in the in-memory history.
+const HISTORY_LIMIT: usize = 200;
+
+/// Longest the scheduler sleeps before re-reading the report catalog, so that
+/// added or edited schedules are picked up without a restart.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+    /// The previous run of the same report was still in progress.
+    Skipped,
+}
+
+/// One scheduled execution of a report.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunRecord {
+    pub report: String,
+    pub scheduled_for: DateTime<Utc>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub status: RunStatus,
+    /// Storage locations of the files the run produced.
+    pub files: Vec<String>,
+    pub error: Option<String>,
+}
+
+/// A report with a schedule, as reported by `GET /schedules`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleInfo {
+    pub report: String,
+    pub schedule: String,
+    pub next_run: Option<DateTime<Utc>>,
+    pub running: bool,
+}
+
+/// In-process cron scheduler for reports that define a `schedule`.
+///
+/// Each tick reloads the report catalog, starts every report whose schedule
+/// fired since the previous tick, writes its files into `EXPORT_DIR` and
+/// hands them to the configured export storage.
+/// A report that is still running when it fires again is skipped.
+#[derive(Default)]
+pub struct Scheduler {
+    running: Mutex<HashSet<String>>,
+    history: Mutex<VecDeque<RunRecord>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Scheduler::default())
+    }
+
+    /// Spawn the scheduler loop on the current Tokio runtime.
+    pub fn start(
+        self: &Arc<Self>,
+        pool: Arc<DbPool>,
+        config: Config,
+        storage: Arc<dyn ExportStorage>,
+        limiter: Arc<ExportLimiter>,
+        jobs: Arc<Jobs>,
+    ) {
+        let scheduler = self.clone();
+        tokio::spawn(async move {
+            scheduler.run_loop(pool, config, storage, limiter, jobs).await;
+        });
+    }
+
+    async fn run_loop(
+        self: Arc<Self>,
+        pool: Arc<DbPool>,
+        config: Config,
+        storage: Arc<dyn ExportStorage>,
+        limiter: Arc<ExportLimiter>,
+        jobs: Arc<Jobs>,
+    ) {
+        let mut last_tick = Utc::now();
+        let mut last_good: Option<ReportCatalog> = None;
+
+        loop {
+            // A failed reload keeps the schedules of the last catalog running
+            match ReportCatalog::load(&pool, &config).await {
+                Ok(catalog) => last_good = Some(catalog),
+                Err(e) => {
+                    tracing::error!(error = %e, "Scheduler: failed to load reports");
+                }
+            }
+            let Some(catalog) = last_good.as_ref() else {
+                tokio::time::sleep(MAX_SLEEP).await;
+                continue;
+            };
+
+            let now = Utc::now();
+            let mut next_wake = now + chrono::Duration::from_std(MAX_SLEEP).unwrap();
+
+            for report in catalog.list() {
+                let Some(schedule) = report.schedule.as_deref().and_then(|s| parse_schedule(s).ok())
+                else {
+                    continue;
+                };
+
+                // No new runs once the server is shutting down.
+                if let Some(due) = due(&schedule, &last_tick, now) {
+                    if !jobs.is_draining() {
+                        self.trigger(
+                            pool.clone(),
+                            config.clone(),
+                            storage.clone(),
+                            limiter.clone(),
+                            jobs.clone(),
+                            report.clone(),
+                            due,
+                        );
+                    }
+                }
+                if let Some(next) = schedule.after(&now).next() {
+                    next_wake = next_wake.min(next);
+                }
+            }
+
+            last_tick = now;
+            let sleep_for = (next_wake - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(sleep_for.max(Duration::from_millis(200))).await;
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)] // the loop's shared state, plus the run
+    fn trigger(
+        self: &Arc<Self>,
+        pool: Arc<DbPool>,
+        config: Config,
+        storage: Arc<dyn ExportStorage>,
+        limiter: Arc<ExportLimiter>,
+        jobs: Arc<Jobs>,
+        report: ReportDefinition,
+        scheduled_for: DateTime<Utc>,
+    ) {
+        let started_at = Utc::now();
+        // Released even if the run panics, so the report keeps its schedule
+        let Some(guard) = self.claim(&report.name, scheduled_for) else {
+            return;
+        };
+        let span = tracing::info_span!(
+            "scheduled_report",
+            report = %report.name,
+            scheduled_for = %scheduled_for.to_rfc3339()
+        );
+        let run = async move {
+            let output_dir = PathBuf::from(&config.export_dir);
+            let work = async {
+                // Scheduled runs share the export slots with HTTP callers and
+                // wait for one rather than failing.
+                let _permit = limiter.acquire_background().await;
+                tracing::info!("Scheduler: running report");
+                let mut output = JobOutput::default();
+                for file in run_report(pool, &config, &report, &output_dir).await? {
+                    let stored = storage::store(storage.as_ref(), &file.path).await?;
+                    output.add(&file, &stored);
+                }
+                Ok::<_, crate::error::AppError>((output.locations.clone(), output))
+            };
+            let request = JobRequest {
+                kind: JobKind::ScheduledReport,
+                name: Some(report.name.clone()),
+                requested_by: "scheduler".to_string(),
+                parameters: serde_json::json!({
+                    "scheduled_for": scheduled_for,
+                    "report": &report,
+                }),
+            };
+            let result = jobs.run(request, work).await;
+
+            let (status, files, error) = match result {
+                Ok(files) => (RunStatus::Succeeded, files, None),
+                Err(e) => {
+                    tracing::error!(error = %e, "Scheduler: report failed");
+                    (RunStatus::Failed, Vec::new(), Some(e.to_string()))
+                }
+            };
+
+            let scheduler = guard.scheduler.clone();
+            drop(guard);
+            scheduler.record(RunRecord {
+                report: report.name,
+                scheduled_for,
+                started_at,
+                finished_at: Utc::now(),
+                status,
+                files,
+                error,
+            });
+        };
+        tokio::spawn(run.instrument(span));
+    }
+
+    /// Mark `report` as running, or record a skipped run if it already is.
+    fn claim(self: &Arc<Self>, report: &str, scheduled_for: DateTime<Utc>) -> Option<RunningGuard> {
+        if !self.running.lock().unwrap().insert(report.to_string()) {
+            tracing::warn!(
+                report,
+                "Scheduler: skipping report, previous run still in progress"
+            );
+            let now = Utc::now();
+            self.record(RunRecord {
+                report: report.to_string(),
+                scheduled_for,
+                started_at: now,
+                finished_at: now,
+                status: RunStatus::Skipped,
+                files: Vec::new(),
+                error: None,
+            });
+            return None;
+        }
+        Some(RunningGuard {
+            scheduler: self.clone(),
+            report: report.to_string(),
+        })
+    }
+
+    fn record(&self, run: RunRecord) {
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY_LIMIT {
+            history.pop_front();
+        }
+        history.push_back(run);
+    }
+
+    /// Finished and skipped runs, most recent first.
+    pub fn history(&self) -> Vec<RunRecord> {
+        self.history.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    pub fn is_running(&self, report: &str) -> bool {
+        self.running.lock().unwrap().contains(report)
+    }
+
+    /// Scheduled runs in progress.
+    pub fn running_count(&self) -> usize {
+        self.running.lock().unwrap().len()
+    }
+
+    /// Scheduled reports from `catalog` with their next run time.
+    pub fn schedules(&self, catalog: &ReportCatalog) -> Vec<ScheduleInfo> {
+        let now = Utc::now();
+        catalog
+            .list()
+            .filter_map(|report| {
+                let expr = report.schedule.as_ref()?;
+                let next_run = parse_schedule(expr).ok().and_then(|s| s.after(&now).next());
+                Some(ScheduleInfo {
+                    report: report.name.clone(),
+                    schedule: expr.clone(),
+                    next_run,
+                    running: self.is_running(&report.name),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The first time `schedule` fires after `last_tick`, if that is not later
+/// than `now`. Fires missed in between (e.g. after a long sleep) collapse into
+/// this one run.
+fn due(
+    schedule: &cron::Schedule,
+    last_tick: &DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    schedule.after(last_tick).next().filter(|due| *due <= now)
+}
+
+/// Marks a report as running until dropped.
+struct RunningGuard {
+    scheduler: Arc<Scheduler>,
+    report: String,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.scheduler.running.lock().unwrap().remove(&self.report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn next_fire(expr: &str, after: DateTime<Utc>) -> DateTime<Utc> {
+        parse_schedule(expr).unwrap().after(&after).next().unwrap()
+    }
+
+    #[test]
+    fn next_fire_follows_day_of_week() {
+        // 2026-10-16 is a Friday.
+        let friday_evening = at(2026, 10, 16, 18, 0);
+        assert_eq!(
+            next_fire("0 2 * * Mon", friday_evening),
+            at(2026, 10, 19, 2, 0)
+        );
+        assert_eq!(
+            next_fire("0 9 * * Mon-Fri", friday_evening),
+            at(2026, 10, 19, 9, 0)
+        );
+        assert_eq!(
+            next_fire("0 9 * * Mon-Fri", at(2026, 10, 16, 8, 0)),
+            at(2026, 10, 16, 9, 0)
+        );
+        // A fire exactly at `after` is not repeated.
+        assert_eq!(
+            next_fire("0 2 * * Mon", at(2026, 10, 19, 2, 0)),
+            at(2026, 10, 26, 2, 0)
+        );
+    }
+
+    #[test]
+    fn next_fire_crosses_month_and_year_ends() {
+        assert_eq!(
+            next_fire("0 0 1 * *", at(2026, 1, 31, 12, 0)),
+            at(2026, 2, 1, 0, 0)
+        );
+        // Months without a 31st are skipped.
+        assert_eq!(
+            next_fire("0 0 31 * *", at(2026, 1, 31, 12, 0)),
+            at(2026, 3, 31, 0, 0)
+        );
+        assert_eq!(
+            next_fire("0 0 29 2 *", at(2026, 3, 1, 0, 0)),
+            at(2028, 2, 29, 0, 0)
+        );
+        assert_eq!(
+            next_fire("30 23 * * *", at(2026, 12, 31, 23, 45)),
+            at(2027, 1, 1, 23, 30)
+        );
+        // Six fields keep their seconds.
+        assert_eq!(
+            next_fire("15 0 0 1 1 *", at(2026, 10, 18, 0, 0)),
+            at(2027, 1, 1, 0, 0) + chrono::Duration::seconds(15)
+        );
+    }
+
+    #[test]
+    fn due_only_when_a_fire_has_passed() {
+        let nightly = parse_schedule("0 2 * * *").unwrap();
+        let last_tick = at(2026, 10, 18, 1, 59);
+        assert_eq!(due(&nightly, &last_tick, at(2026, 10, 18, 1, 59)), None);
+        assert_eq!(
+            due(&nightly, &last_tick, at(2026, 10, 18, 2, 0)),
+            Some(at(2026, 10, 18, 2, 0))
+        );
+        // Three missed nights run once, for the first of them.
+        assert_eq!(
+            due(&nightly, &last_tick, at(2026, 10, 21, 3, 0)),
+            Some(at(2026, 10, 18, 2, 0))
+        );
+    }
+
+    #[test]
+    fn overlapping_run_is_skipped() {
+        let scheduler = Scheduler::new();
+        let first = scheduler.claim("daily", at(2026, 10, 18, 2, 0)).unwrap();
+        assert!(scheduler.is_running("daily"));
+
+        assert!(scheduler.claim("daily", at(2026, 10, 19, 2, 0)).is_none());
+        let other = scheduler.claim("weekly", at(2026, 10, 19, 2, 0)).unwrap();
+        assert_eq!(scheduler.running_count(), 2);
+
+        let history = scheduler.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].report, "daily");
+        assert_eq!(history[0].status, RunStatus::Skipped);
+        assert_eq!(history[0].scheduled_for, at(2026, 10, 19, 2, 0));
+
+        drop(first);
+        drop(other);
+        assert_eq!(scheduler.running_count(), 0);
+        assert!(scheduler.claim("daily", at(2026, 10, 20, 2, 0)).is_some());
+    }
+}
@@ -0,0 +1,105 @@
+use super::{ExportStorage, StoredExport};
+use crate::error::AppError;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Exports kept on local disk under `root`, downloadable through the server's
+/// `GET /downloads/{key}` route with an HMAC-signed, expiring query string.
+pub struct LocalStorage {
+    root: PathBuf,
+    base_url: String,
+    signing_key: Vec<u8>,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>, base_url: &str, signing_key: &[u8]) -> Self {
+        LocalStorage {
+            root: root.into(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            signing_key: signing_key.to_vec(),
+        }
+    }
+
+    fn mac(&self, key: &str, expires: i64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.signing_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\n{}", key, expires).as_bytes());
+        mac
+    }
+
+    /// Check a download request's `expires` / `signature` query parameters.
+    pub fn verify(&self, key: &str, expires: i64, signature: &str) -> bool {
+        if expires < chrono::Utc::now().timestamp() {
+            return false;
+        }
+        match hex::decode(signature) {
+            Ok(sig) => self.mac(key, expires).verify_slice(&sig).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Path of the stored file for `key`, if `key` is a plain file name that exists.
+    pub fn resolve(&self, key: &str) -> Option<PathBuf> {
+        if !valid_key(key) {
+            return None;
+        }
+        let path = self.root.join(key);
+        path.is_file().then_some(path)
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with('.')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+#[async_trait]
+impl ExportStorage for LocalStorage {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(&self, key: &str, path: &Path) -> Result<StoredExport, AppError> {
+        if !valid_key(key) {
+            return Err(AppError::Storage(format!("invalid storage key '{}'", key)));
+        }
+
+        tokio::fs::create_dir_all(&self.root).await?;
+        let target = self.root.join(key);
+        let same_file = match (path.canonicalize(), target.canonicalize()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        };
+        if !same_file {
+            // Exports normally already live in the export dir; move strays in.
+            if tokio::fs::rename(path, &target).await.is_err() {
+                tokio::fs::copy(path, &target).await?;
+                tokio::fs::remove_file(path).await?;
+            }
+        }
+
+        let size = tokio::fs::metadata(&target).await?.len();
+        Ok(StoredExport {
+            key: key.to_string(),
+            location: target.to_string_lossy().into_owned(),
+            size,
+        })
+    }
+
+    fn signed_url(&self, key: &str, ttl: Duration) -> Result<String, AppError> {
+        let expires = chrono::Utc::now().timestamp() + ttl.as_secs() as i64;
+        let signature = hex::encode(self.mac(key, expires).finalize().into_bytes());
+        Ok(format!(
+            "{}/downloads/{}?expires={}&signature={}",
+            self.base_url, key, expires, signature
+        ))
+    }
+}
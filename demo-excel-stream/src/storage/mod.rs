@@ -0,0 +1,102 @@
+//! Where finished exports live and how clients download them.
+
+mod local;
+mod s3;
+
+pub use local::LocalStorage;
+pub use s3::{S3Config, S3Storage};
+
+use crate::config::Config;
+use crate::error::AppError;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A finished export after it has been handed to an `ExportStorage`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredExport {
+    pub key: String,
+    /// Local path or `s3://bucket/key`.
+    pub location: String,
+    pub size: u64,
+}
+
+/// A stored export plus a time-limited download link, as returned by the API.
+#[derive(Debug, Clone, Serialize)]
+pub struct PublishedExport {
+    #[serde(flatten)]
+    pub stored: StoredExport,
+    pub download_url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Destination for finished export files.
+#[async_trait]
+pub trait ExportStorage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Take ownership of the finished file at `path` and store it under `key`.
+    /// Backends other than local disk remove the local file once stored.
+    async fn put(&self, key: &str, path: &Path) -> Result<StoredExport, AppError>;
+
+    /// URL from which `key` can be downloaded until `ttl` has elapsed.
+    fn signed_url(&self, key: &str, ttl: Duration) -> Result<String, AppError>;
+}
+
+/// Which `ExportStorage` the application uses (`STORAGE_BACKEND`).
+#[derive(Debug, Clone)]
+pub enum StorageBackend {
+    Local,
+    S3(S3Config),
+}
+
+/// Build the configured backend. Local storage is always rooted at
+/// `config.export_dir`, since that is where exports are written first.
+pub fn from_config(
+    config: &Config,
+    local: Arc<LocalStorage>,
+) -> Result<Arc<dyn ExportStorage>, AppError> {
+    match &config.storage {
+        StorageBackend::Local => Ok(local),
+        StorageBackend::S3(s3) => Ok(Arc::new(S3Storage::new(s3.clone())?)),
+    }
+}
+
+/// Store the export at `path`, keyed by its file name.
+pub async fn store(storage: &dyn ExportStorage, path: &Path) -> Result<StoredExport, AppError> {
+    let key = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or_else(|| AppError::Storage(format!("invalid export path {}", path.display())))?;
+    storage.put(&key, path).await
+}
+
+/// Store the export at `path` and sign a download URL for it.
+pub async fn publish(
+    storage: &dyn ExportStorage,
+    path: &Path,
+    ttl: Duration,
+) -> Result<PublishedExport, AppError> {
+    let stored = store(storage, path).await?;
+    let download_url = storage.signed_url(&stored.key, ttl)?;
+    let expires_at = Utc::now() + chrono::Duration::from_std(ttl).unwrap_or_default();
+
+    Ok(PublishedExport {
+        stored,
+        download_url,
+        expires_at,
+    })
+}
+
+pub(crate) fn content_type(key: &str) -> &'static str {
+    if key.ends_with(".xlsx") {
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+    } else if key.ends_with(".csv") {
+        "text/csv"
+    } else {
+        "application/octet-stream"
+    }
+}
@@ -0,0 +1,425 @@
+use crate::xlsx_patch::SheetPatch;
+use excelstream::types::CellStyle;
+use rust_xlsxwriter::{
+    utility::column_number_to_name, Color, ConditionalFormatFormula, Format, FormatBorder,
+    Worksheet, XlsxError,
+};
+use serde::{Deserialize, Serialize};
+
+const HEADER_FILL: u32 = 0xD9E1F2;
+const BAND_FILL: u32 = 0xF2F2F2;
+const RED_FILL: u32 = 0xFFC7CE;
+const RED_FONT: u32 = 0x9C0006;
+const GREEN_FILL: u32 = 0xC6EFCE;
+const GREEN_FONT: u32 = 0x006100;
+const YELLOW_FILL: u32 = 0xFFEB9C;
+const YELLOW_FONT: u32 = 0x9C5700;
+
+/// Optional styling and layout applied to an exported sheet. Everything is
+/// off by default, which produces the plain values-only workbook.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StyleOptions {
+    /// Bold, filled header row.
+    pub header: bool,
+    /// Alternating row background.
+    pub banded_rows: bool,
+    /// Colour the `status` column: Cancelled red, Delivered green.
+    pub status_colors: bool,
+    /// Highlight rows whose `payment_status` is Failed.
+    pub highlight_failed: bool,
+    /// Keep the header row visible while scrolling.
+    pub freeze_header: bool,
+    /// Filter dropdowns on the header over the whole data range.
+    pub autofilter: bool,
+    /// Column widths sized from the header and the first sampled rows.
+    pub auto_width: bool,
+}
+
+impl StyleOptions {
+    pub fn all() -> Self {
+        StyleOptions {
+            header: true,
+            banded_rows: true,
+            status_colors: true,
+            highlight_failed: true,
+            freeze_header: true,
+            autofilter: true,
+            auto_width: true,
+        }
+    }
+
+    /// Whether any option changes the sheet layout rather than cell styles.
+    pub fn has_layout(&self) -> bool {
+        self.freeze_header || self.autofilter || self.auto_width
+    }
+
+    /// Parse `all`, `none`, or a comma-separated list such as
+    /// `header,status_colors`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut options = StyleOptions::default();
+        for part in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part {
+                "all" => options = StyleOptions::all(),
+                "none" => options = StyleOptions::default(),
+                "header" => options.header = true,
+                "banded_rows" => options.banded_rows = true,
+                "status_colors" => options.status_colors = true,
+                "highlight_failed" => options.highlight_failed = true,
+                "freeze_header" => options.freeze_header = true,
+                "autofilter" => options.autofilter = true,
+                "auto_width" => options.auto_width = true,
+                other => return Err(format!("unknown style option '{}'", other)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Tracks the widest value seen per column over the header and the first
+/// `SAMPLE_ROWS` data rows, so widths can be chosen without holding rows.
+pub struct ColumnWidths {
+    chars: Vec<usize>,
+    sampled: usize,
+}
+
+impl ColumnWidths {
+    pub const SAMPLE_ROWS: usize = 1000;
+    const MIN_WIDTH: usize = 8;
+    const MAX_WIDTH: usize = 60;
+
+    pub fn new<S: AsRef<str>>(headers: &[S]) -> Self {
+        ColumnWidths {
+            chars: headers.iter().map(|h| h.as_ref().chars().count()).collect(),
+            sampled: 0,
+        }
+    }
+
+    /// Whether the next row should still be measured.
+    pub fn sampling(&self) -> bool {
+        self.sampled < Self::SAMPLE_ROWS
+    }
+
+    /// Record the display length of each cell in one row.
+    pub fn observe(&mut self, lengths: impl IntoIterator<Item = usize>) {
+        if !self.sampling() {
+            return;
+        }
+        for (col, len) in lengths.into_iter().enumerate() {
+            match self.chars.get_mut(col) {
+                Some(max) => *max = (*max).max(len),
+                None => self.chars.push(len),
+            }
+        }
+        self.sampled += 1;
+    }
+
+    /// Column widths in Excel character units, with a little padding.
+    pub fn widths(&self) -> Vec<f64> {
+        self.chars
+            .iter()
+            .map(|&len| (len + 2).clamp(Self::MIN_WIDTH, Self::MAX_WIDTH) as f64)
+            .collect()
+    }
+}
+
+/// Freeze the header, add an autofilter over rows `0..=last_row` and set
+/// sampled column widths on a rust_xlsxwriter sheet, as `options` asks.
+pub fn apply_xlsx_layout(
+    worksheet: &mut Worksheet,
+    options: &StyleOptions,
+    widths: &ColumnWidths,
+    last_row: u32,
+) -> Result<(), XlsxError> {
+    let widths = widths.widths();
+    if options.freeze_header {
+        worksheet.set_freeze_panes(1, 0)?;
+    }
+    if options.autofilter && !widths.is_empty() {
+        worksheet.autofilter(0, 0, last_row, (widths.len() - 1) as u16)?;
+    }
+    if options.auto_width {
+        for (col, width) in widths.iter().enumerate() {
+            worksheet.set_column_width(col as u16, *width)?;
+        }
+    }
+    Ok(())
+}
+
+/// The same layout for a finished excelstream workbook, which has no API for
+/// it, expressed as XML to splice into the sheet and workbook parts.
+pub fn excelstream_layout_patch(
+    options: &StyleOptions,
+    widths: &ColumnWidths,
+    sheet_name: &str,
+    last_row: u32,
+) -> SheetPatch {
+    let widths = widths.widths();
+    let mut patch = SheetPatch::default();
+
+    if options.freeze_header {
+        patch.before_sheet_data.push_str(
+            "<sheetViews><sheetView tabSelected=\"1\" workbookViewId=\"0\">\
+             <pane ySplit=\"1\" topLeftCell=\"A2\" activePane=\"bottomLeft\" state=\"frozen\"/>\
+             <selection pane=\"bottomLeft\" activeCell=\"A2\" sqref=\"A2\"/>\
+             </sheetView></sheetViews>",
+        );
+    }
+    if options.auto_width && !widths.is_empty() {
+        patch.before_sheet_data.push_str("<cols>");
+        for (col, width) in widths.iter().enumerate() {
+            patch.before_sheet_data.push_str(&format!(
+                "<col min=\"{0}\" max=\"{0}\" width=\"{1}\" customWidth=\"1\"/>",
+                col + 1,
+                width
+            ));
+        }
+        patch.before_sheet_data.push_str("</cols>");
+    }
+    if options.autofilter && !widths.is_empty() {
+        let last_col = column_number_to_name((widths.len() - 1) as u16);
+        patch.before_end = format!("<autoFilter ref=\"A1:{}{}\"/>", last_col, last_row + 1);
+        patch.defined_names = format!(
+            "<definedName name=\"_xlnm._FilterDatabase\" localSheetId=\"0\" hidden=\"1\">\
+             '{}'!$A$1:${}${}</definedName>",
+            sheet_name.replace('\'', "''"),
+            last_col,
+            last_row + 1
+        );
+    }
+
+    patch
+}
+
+/// Header cell format for rust_xlsxwriter, or `None` for a plain header.
+pub fn xlsx_header_format(options: &StyleOptions) -> Option<Format> {
+    options.header.then(|| {
+        Format::new()
+            .set_bold()
+            .set_background_color(Color::RGB(HEADER_FILL))
+            .set_border_bottom(FormatBorder::Thin)
+    })
+}
+
+/// Add Excel conditional formats for `options` over data rows `1..=last_row`.
+/// `columns` are the result column names, used to locate `status` and
+/// `payment_status`. Rules added first take precedence.
+pub fn add_xlsx_conditional_formats(
+    worksheet: &mut Worksheet,
+    options: &StyleOptions,
+    columns: &[String],
+    last_row: u32,
+) -> Result<(), XlsxError> {
+    if last_row == 0 || columns.is_empty() {
+        return Ok(());
+    }
+    let last_col = (columns.len() - 1) as u16;
+    let position = |name: &str| columns.iter().position(|c| c == name).map(|i| i as u16);
+
+    if options.status_colors {
+        if let Some(col) = position("status") {
+            let cell = format!("{}2", column_number_to_name(col));
+            for (value, fill, font) in [
+                ("Cancelled", RED_FILL, RED_FONT),
+                ("Delivered", GREEN_FILL, GREEN_FONT),
+            ] {
+                let rule = ConditionalFormatFormula::new()
+                    .set_rule(format!("={}=\"{}\"", cell, value).as_str())
+                    .set_format(fill_format(fill, font));
+                worksheet.add_conditional_format(1, col, last_row, col, &rule)?;
+            }
+        }
+    }
+
+    if options.highlight_failed {
+        if let Some(col) = position("payment_status") {
+            let rule = ConditionalFormatFormula::new()
+                .set_rule(format!("=${}2=\"Failed\"", column_number_to_name(col)).as_str())
+                .set_format(fill_format(YELLOW_FILL, YELLOW_FONT));
+            worksheet.add_conditional_format(1, 0, last_row, last_col, &rule)?;
+        }
+    }
+
+    if options.banded_rows {
+        let rule = ConditionalFormatFormula::new()
+            .set_rule("=MOD(ROW(),2)=0")
+            .set_format(Format::new().set_background_color(Color::RGB(BAND_FILL)));
+        worksheet.add_conditional_format(1, 0, last_row, last_col, &rule)?;
+    }
+
+    Ok(())
+}
+
+fn fill_format(fill: u32, font: u32) -> Format {
+    Format::new()
+        .set_background_color(Color::RGB(fill))
+        .set_font_color(Color::RGB(font))
+}
+
+/// excelstream has no conditional formatting or custom fills, so the same
+/// rules are applied as static per-cell styles from its built-in palette.
+/// Banded rows are not available on this backend.
+pub fn excelstream_cell_style(
+    options: &StyleOptions,
+    column: &str,
+    value: &str,
+    payment_failed: bool,
+) -> CellStyle {
+    if options.status_colors && column == "status" {
+        match value {
+            "Cancelled" => return CellStyle::HighlightRed,
+            "Delivered" => return CellStyle::HighlightGreen,
+            _ => {}
+        }
+    }
+    if options.highlight_failed && payment_failed {
+        return CellStyle::HighlightYellow;
+    }
+    CellStyle::Default
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_xlsxwriter::Workbook;
+    use std::io::{Cursor, Read};
+
+    fn columns() -> Vec<String> {
+        ["order_id", "status", "total", "payment_status"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    /// `xl/worksheets/sheet1.xml` of a workbook with the conditional formats
+    /// for `options` over `last_row` data rows.
+    fn conditional_sheet_xml(options: &StyleOptions, columns: &[String], last_row: u32) -> String {
+        let mut workbook = Workbook::new();
+        let worksheet = workbook.add_worksheet();
+        add_xlsx_conditional_formats(worksheet, options, columns, last_row).unwrap();
+        let buffer = workbook.save_to_buffer().unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(buffer)).unwrap();
+        let mut xml = String::new();
+        archive
+            .by_name("xl/worksheets/sheet1.xml")
+            .unwrap()
+            .read_to_string(&mut xml)
+            .unwrap();
+        xml
+    }
+
+    fn conditional_formats(xml: &str) -> Vec<&str> {
+        xml.split("<conditionalFormatting ")
+            .skip(1)
+            .map(|s| &s[..s.find("</conditionalFormatting>").unwrap()])
+            .collect()
+    }
+
+    #[test]
+    fn conditional_formats_cover_the_data_rows() {
+        let xml = conditional_sheet_xml(&StyleOptions::all(), &columns(), 10);
+
+        assert_eq!(
+            conditional_formats(&xml),
+            vec![
+                "sqref=\"B2:B11\">\
+                 <cfRule type=\"expression\" dxfId=\"0\" priority=\"1\">\
+                 <formula>B2=\"Cancelled\"</formula></cfRule>\
+                 <cfRule type=\"expression\" dxfId=\"1\" priority=\"2\">\
+                 <formula>B2=\"Delivered\"</formula></cfRule>",
+                "sqref=\"A2:D11\">\
+                 <cfRule type=\"expression\" dxfId=\"2\" priority=\"3\">\
+                 <formula>$D2=\"Failed\"</formula></cfRule>\
+                 <cfRule type=\"expression\" dxfId=\"3\" priority=\"4\">\
+                 <formula>MOD(ROW(),2)=0</formula></cfRule>",
+            ]
+        );
+    }
+
+    #[test]
+    fn conditional_formats_skip_missing_columns_and_empty_sheets() {
+        let narrow = ["order_id", "total"].map(String::from).to_vec();
+        let xml = conditional_sheet_xml(&StyleOptions::all(), &narrow, 10);
+        let rules = conditional_formats(&xml);
+        assert_eq!(rules.len(), 1);
+        assert!(rules[0].starts_with("sqref=\"A2:B11\""));
+        assert!(rules[0].contains("MOD(ROW(),2)=0"));
+
+        let xml = conditional_sheet_xml(&StyleOptions::all(), &columns(), 0);
+        assert!(conditional_formats(&xml).is_empty());
+
+        let status_only = StyleOptions {
+            status_colors: true,
+            ..Default::default()
+        };
+        let xml = conditional_sheet_xml(&status_only, &columns(), 10);
+        let rules = conditional_formats(&xml);
+        assert_eq!(rules.len(), 1);
+        assert!(rules[0].starts_with("sqref=\"B2:B11\""));
+    }
+
+    #[test]
+    fn column_widths_stop_sampling_after_sample_rows() {
+        let mut widths = ColumnWidths::new(&["id", "customer_name"]);
+        for _ in 0..ColumnWidths::SAMPLE_ROWS - 1 {
+            widths.observe([4, 20]);
+        }
+        assert!(widths.sampling());
+        widths.observe([30, 1]);
+        assert!(!widths.sampling());
+
+        // Rows past the sample no longer count.
+        widths.observe([200, 200, 200]);
+        assert_eq!(widths.widths(), vec![32.0, 22.0]);
+    }
+
+    #[test]
+    fn column_widths_are_padded_and_clamped() {
+        let mut widths = ColumnWidths::new(&["id", "customer_name", "notes"]);
+        widths.observe([1, 3, 500, 12]);
+        // Header "customer_name" is 13 characters; a cell past the headers
+        // adds a column.
+        assert_eq!(widths.widths(), vec![8.0, 15.0, 60.0, 14.0]);
+    }
+
+    #[test]
+    fn layout_patch_freezes_filters_and_sizes_columns() {
+        let mut widths = ColumnWidths::new(&["id", "customer_name"]);
+        widths.observe([3, 20]);
+        let patch = excelstream_layout_patch(&StyleOptions::all(), &widths, "Bob's orders", 10);
+
+        assert_eq!(
+            patch.before_sheet_data,
+            "<sheetViews><sheetView tabSelected=\"1\" workbookViewId=\"0\">\
+             <pane ySplit=\"1\" topLeftCell=\"A2\" activePane=\"bottomLeft\" state=\"frozen\"/>\
+             <selection pane=\"bottomLeft\" activeCell=\"A2\" sqref=\"A2\"/>\
+             </sheetView></sheetViews>\
+             <cols>\
+             <col min=\"1\" max=\"1\" width=\"8\" customWidth=\"1\"/>\
+             <col min=\"2\" max=\"2\" width=\"22\" customWidth=\"1\"/>\
+             </cols>"
+        );
+        assert_eq!(patch.before_end, "<autoFilter ref=\"A1:B11\"/>");
+        assert_eq!(
+            patch.defined_names,
+            "<definedName name=\"_xlnm._FilterDatabase\" localSheetId=\"0\" hidden=\"1\">\
+             'Bob''s orders'!$A$1:$B$11</definedName>"
+        );
+        assert!(patch.after_sheet_data.is_empty());
+    }
+
+    #[test]
+    fn layout_patch_is_empty_without_layout_options() {
+        let widths = ColumnWidths::new(&["id"]);
+        let options = StyleOptions {
+            header: true,
+            banded_rows: true,
+            ..Default::default()
+        };
+        assert!(!options.has_layout());
+        assert!(excelstream_layout_patch(&options, &widths, "Sheet1", 10).is_empty());
+
+        let no_columns = ColumnWidths::new::<&str>(&[]);
+        let patch = excelstream_layout_patch(&StyleOptions::all(), &no_columns, "Sheet1", 10);
+        assert!(patch.before_end.is_empty() && patch.defined_names.is_empty());
+    }
+}
@@ -0,0 +1,424 @@
+//! Overview sheets built alongside the detail rows: order count and revenue
+//! grouped by status, country, order month and payment method.
+//!
+//! Totals are accumulated while rows are written, so a summary costs no extra
+//! queries and always matches the detail sheet exactly.
+
+use crate::row::CellData;
+use rust_xlsxwriter::{Chart, ChartDataLabel, ChartType, Format, Workbook, XlsxError};
+use std::collections::BTreeMap;
+
+const AMOUNT_COLUMN: &str = "total_amount";
+const BLANK: &str = "(blank)";
+
+/// A grouping of the summary, one sheet each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dimension {
+    Status,
+    Country,
+    Month,
+    PaymentMethod,
+}
+
+impl Dimension {
+    pub const ALL: [Dimension; 4] = [
+        Dimension::Status,
+        Dimension::Country,
+        Dimension::Month,
+        Dimension::PaymentMethod,
+    ];
+
+    /// Result column the dimension is read from.
+    pub fn column(&self) -> &'static str {
+        match self {
+            Dimension::Status => "status",
+            Dimension::Country => "country",
+            Dimension::Month => "order_date",
+            Dimension::PaymentMethod => "payment_method",
+        }
+    }
+
+    pub fn sheet_name(&self) -> &'static str {
+        match self {
+            Dimension::Status => "By Status",
+            Dimension::Country => "By Country",
+            Dimension::Month => "By Month",
+            Dimension::PaymentMethod => "By Payment Method",
+        }
+    }
+
+    /// Whether a data sheet called `name` would clash with a summary sheet.
+    /// Excel compares sheet names ignoring case.
+    pub fn is_summary_sheet_name(name: &str) -> bool {
+        Dimension::ALL
+            .iter()
+            .any(|d| d.sheet_name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn header(&self) -> &'static str {
+        match self {
+            Dimension::Status => "Status",
+            Dimension::Country => "Country",
+            Dimension::Month => "Month",
+            Dimension::PaymentMethod => "Payment Method",
+        }
+    }
+
+    fn key(&self, value: &str) -> String {
+        match self {
+            // Dates are rendered as YYYY-MM-DD, so the month is the prefix.
+            Dimension::Month => value.get(..7).unwrap_or(value).to_string(),
+            _ => value.to_string(),
+        }
+    }
+}
+
+/// Count and revenue of one group.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Totals {
+    pub orders: u64,
+    pub revenue: f64,
+}
+
+/// One finished summary sheet.
+#[derive(Debug, Clone)]
+pub struct SummaryTable {
+    pub dimension: Dimension,
+    /// Groups in key order (months chronologically).
+    pub rows: Vec<(String, Totals)>,
+    pub total: Totals,
+}
+
+/// Incremental aggregates over the rows of an export. Dimensions whose column
+/// is not in the result are skipped; without `total_amount` revenue stays 0.
+pub struct SummaryBuilder {
+    dimensions: Vec<(Dimension, usize)>,
+    amount: Option<usize>,
+    groups: Vec<BTreeMap<String, Totals>>,
+    total: Totals,
+}
+
+impl SummaryBuilder {
+    /// `columns` are the result column names, in order.
+    pub fn new<S: AsRef<str>>(columns: &[S]) -> Self {
+        let position = |name: &str| columns.iter().position(|c| c.as_ref() == name);
+        let dimensions: Vec<(Dimension, usize)> = Dimension::ALL
+            .iter()
+            .filter_map(|d| position(d.column()).map(|idx| (*d, idx)))
+            .collect();
+        SummaryBuilder {
+            groups: vec![BTreeMap::new(); dimensions.len()],
+            dimensions,
+            amount: position(AMOUNT_COLUMN),
+            total: Totals::default(),
+        }
+    }
+
+    /// Whether any summary sheet can be produced from these columns.
+    pub fn is_empty(&self) -> bool {
+        self.dimensions.is_empty()
+    }
+
+    /// Add one row. `text` returns the displayed value of column `idx`,
+    /// `number` its numeric value.
+    pub fn observe<'a>(
+        &mut self,
+        text: impl Fn(usize) -> Option<&'a str>,
+        number: impl Fn(usize) -> Option<f64>,
+    ) {
+        let revenue = self.amount.and_then(&number).unwrap_or(0.0);
+        for ((dimension, idx), groups) in self.dimensions.iter().zip(&mut self.groups) {
+            let key = match text(*idx) {
+                Some(value) if !value.is_empty() => dimension.key(value),
+                _ => BLANK.to_string(),
+            };
+            let totals = groups.entry(key).or_default();
+            totals.orders += 1;
+            totals.revenue += revenue;
+        }
+        self.total.orders += 1;
+        self.total.revenue += revenue;
+    }
+
+    /// Add one row of backend-neutral cells.
+    pub fn observe_cells(&mut self, cells: &[CellData]) {
+        self.observe(
+            |idx| match cells.get(idx) {
+                Some(CellData::Text(s)) => Some(s.as_str()),
+                _ => None,
+            },
+            |idx| match cells.get(idx) {
+                Some(CellData::Number(v)) => Some(*v),
+                Some(CellData::Int(v)) => Some(*v as f64),
+                _ => None,
+            },
+        );
+    }
+
+    pub fn finish(self) -> Vec<SummaryTable> {
+        let total = self.total;
+        self.dimensions
+            .into_iter()
+            .zip(self.groups)
+            .map(|((dimension, _), groups)| SummaryTable {
+                dimension,
+                rows: groups.into_iter().collect(),
+                total,
+            })
+            .collect()
+    }
+}
+
+/// Append one rust_xlsxwriter worksheet per table, after the detail sheet.
+/// With `charts`, a native chart of the table is placed next to it.
+pub fn write_xlsx(
+    workbook: &mut Workbook,
+    tables: &[SummaryTable],
+    header_format: Option<&Format>,
+    charts: bool,
+) -> Result<(), XlsxError> {
+    let money = Format::new().set_num_format("#,##0.00");
+    let bold = Format::new().set_bold();
+
+    for table in tables {
+        let worksheet = workbook.add_worksheet();
+        worksheet.set_name(table.dimension.sheet_name())?;
+
+        for (col, header) in [table.dimension.header(), "Orders", "Revenue"]
+            .iter()
+            .enumerate()
+        {
+            match header_format {
+                Some(format) => {
+                    worksheet.write_string_with_format(0, col as u16, *header, format)?
+                }
+                None => worksheet.write_string(0, col as u16, *header)?,
+            };
+        }
+
+        let mut row = 1u32;
+        for (key, totals) in &table.rows {
+            worksheet.write_string(row, 0, key)?;
+            worksheet.write_number(row, 1, totals.orders as f64)?;
+            worksheet.write_number_with_format(row, 2, totals.revenue, &money)?;
+            row += 1;
+        }
+
+        worksheet.write_string_with_format(row, 0, "Total", &bold)?;
+        worksheet.write_number_with_format(row, 1, table.total.orders as f64, &bold)?;
+        worksheet.write_number_with_format(
+            row,
+            2,
+            table.total.revenue,
+            &money.clone().set_bold(),
+        )?;
+
+        worksheet.set_column_width(0, 20)?;
+        worksheet.set_column_width(1, 12)?;
+        worksheet.set_column_width(2, 16)?;
+
+        if charts && !table.rows.is_empty() {
+            if let Some(chart) = chart(table) {
+                worksheet.insert_chart(1, 4, &chart)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Chart for a summary table, drawn from its rows (not the total):
+/// monthly revenue as columns, status share as a pie, revenue by country as
+/// bars. Payment methods get no chart.
+fn chart(table: &SummaryTable) -> Option<Chart> {
+    let sheet = table.dimension.sheet_name();
+    let last_row = table.rows.len() as u32;
+    let categories = (sheet, 1, 0, last_row, 0);
+    let orders = (sheet, 1, 1, last_row, 1);
+    let revenue = (sheet, 1, 2, last_row, 2);
+
+    let mut chart = match table.dimension {
+        Dimension::Month => {
+            let mut chart = Chart::new(ChartType::Column);
+            chart.title().set_name("Revenue by Month");
+            chart.y_axis().set_num_format("#,##0");
+            chart
+                .add_series()
+                .set_name("Revenue")
+                .set_categories(categories)
+                .set_values(revenue);
+            chart
+        }
+        Dimension::Status => {
+            let mut chart = Chart::new(ChartType::Pie);
+            chart.title().set_name("Orders by Status");
+            chart
+                .add_series()
+                .set_name("Orders")
+                .set_categories(categories)
+                .set_values(orders)
+                .set_data_label(ChartDataLabel::new().show_percentage());
+            chart
+        }
+        Dimension::Country => {
+            let mut chart = Chart::new(ChartType::Bar);
+            chart.title().set_name("Revenue by Country");
+            chart.x_axis().set_num_format("#,##0");
+            chart
+                .add_series()
+                .set_name("Revenue")
+                .set_categories(categories)
+                .set_values(revenue);
+            chart
+        }
+        Dimension::PaymentMethod => return None,
+    };
+    if table.dimension != Dimension::Status {
+        chart.legend().set_hidden();
+    }
+    chart.set_width(640).set_height(360);
+    Some(chart)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    fn text(value: &str) -> CellData {
+        CellData::Text(value.to_string())
+    }
+
+    /// Orders with the columns of the export query that matter here, plus
+    /// one (`order_id`) that does not.
+    fn builder() -> SummaryBuilder {
+        let mut builder = SummaryBuilder::new(&[
+            "order_id",
+            "status",
+            "country",
+            "order_date",
+            "payment_method",
+            "total_amount",
+        ]);
+        for (id, status, country, date, method, amount) in [
+            (1, "Delivered", "DE", "2026-01-15", "card", 100.0),
+            (2, "Cancelled", "FR", "2026-01-31", "paypal", 50.5),
+            (3, "Delivered", "DE", "2026-02-01", "card", 20.25),
+            (4, "Delivered", "", "2025-12-31", "card", 9.25),
+        ] {
+            builder.observe_cells(&[
+                CellData::Int(id),
+                text(status),
+                text(country),
+                text(date),
+                text(method),
+                CellData::Number(amount),
+            ]);
+        }
+        builder
+    }
+
+    fn totals(orders: u64, revenue: f64) -> Totals {
+        Totals { orders, revenue }
+    }
+
+    fn rows(table: &SummaryTable) -> Vec<(&str, Totals)> {
+        table.rows.iter().map(|(k, t)| (k.as_str(), *t)).collect()
+    }
+
+    #[test]
+    fn totals_per_dimension() {
+        let tables = builder().finish();
+        let dimensions: Vec<_> = tables.iter().map(|t| t.dimension).collect();
+        assert_eq!(dimensions, Dimension::ALL);
+        for table in &tables {
+            assert_eq!(table.total, totals(4, 180.0));
+        }
+
+        assert_eq!(
+            rows(&tables[0]),
+            vec![
+                ("Cancelled", totals(1, 50.5)),
+                ("Delivered", totals(3, 129.5))
+            ]
+        );
+        assert_eq!(
+            rows(&tables[1]),
+            vec![
+                ("(blank)", totals(1, 9.25)),
+                ("DE", totals(2, 120.25)),
+                ("FR", totals(1, 50.5)),
+            ]
+        );
+        // Months sort chronologically, across the year end.
+        assert_eq!(
+            rows(&tables[2]),
+            vec![
+                ("2025-12", totals(1, 9.25)),
+                ("2026-01", totals(2, 150.5)),
+                ("2026-02", totals(1, 20.25)),
+            ]
+        );
+        assert_eq!(
+            rows(&tables[3]),
+            vec![("card", totals(3, 129.5)), ("paypal", totals(1, 50.5))]
+        );
+    }
+
+    #[test]
+    fn missing_columns_skip_dimensions_and_revenue() {
+        let mut builder = SummaryBuilder::new(&["country", "status"]);
+        builder.observe_cells(&[text("DE"), text("Delivered")]);
+        let tables = builder.finish();
+
+        let dimensions: Vec<_> = tables.iter().map(|t| t.dimension).collect();
+        assert_eq!(dimensions, vec![Dimension::Status, Dimension::Country]);
+        assert_eq!(rows(&tables[0]), vec![("Delivered", totals(1, 0.0))]);
+        assert_eq!(rows(&tables[1]), vec![("DE", totals(1, 0.0))]);
+
+        assert!(SummaryBuilder::new(&["order_id"]).is_empty());
+    }
+
+    #[test]
+    fn chart_series_cover_the_group_rows() {
+        let mut workbook = Workbook::new();
+        workbook.add_worksheet();
+        write_xlsx(&mut workbook, &builder().finish(), None, true).unwrap();
+        let buffer = workbook.save_to_buffer().unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(buffer)).unwrap();
+
+        let mut charts = Vec::new();
+        for i in 1.. {
+            let Ok(mut file) = archive.by_name(&format!("xl/charts/chart{}.xml", i)) else {
+                break;
+            };
+            let mut xml = String::new();
+            file.read_to_string(&mut xml).unwrap();
+            let ranges: Vec<String> = xml
+                .split("<c:f>")
+                .skip(1)
+                .map(|s| s[..s.find("</c:f>").unwrap()].to_string())
+                .collect();
+            charts.push(ranges);
+        }
+
+        // Status, country and month; payment methods have no chart. The
+        // series stops before the total row.
+        assert_eq!(
+            charts,
+            vec![
+                vec!["'By Status'!$A$2:$A$3", "'By Status'!$B$2:$B$3"],
+                vec!["'By Country'!$A$2:$A$4", "'By Country'!$C$2:$C$4"],
+                vec!["'By Month'!$A$2:$A$4", "'By Month'!$C$2:$C$4"],
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_sheet_names_are_reserved() {
+        assert!(Dimension::is_summary_sheet_name("By Status"));
+        assert!(Dimension::is_summary_sheet_name("by payment method"));
+        assert!(!Dimension::is_summary_sheet_name("Orders"));
+        assert!(!Dimension::is_summary_sheet_name("By Status 2"));
+    }
+}
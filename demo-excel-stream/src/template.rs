@@ -0,0 +1,621 @@
+//! Exports into a user-provided `.xlsx` template.
+//!
+//! The template marks where the data goes with an anchor: a defined name
+//! (e.g. `orders` referring to `'Data'!$B$4`) or a cell whose text is
+//! `{{orders}}`. The export header is written at the anchor and the rows
+//! below it; rows of that sheet from the anchor down are replaced, and every
+//! other part of the template (sheets, styles, images, formulas) is copied
+//! through unchanged. Rows are streamed straight into the output archive.
+
+use crate::error::AppError;
+use crate::protection;
+use crate::row::CellData;
+use rust_xlsxwriter::utility::column_number_to_name;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+pub const DEFAULT_ANCHOR: &str = "orders";
+
+const WORKBOOK: &str = "xl/workbook.xml";
+const WORKBOOK_RELS: &str = "xl/_rels/workbook.xml.rels";
+const CONTENT_TYPES: &str = "[Content_Types].xml";
+const SHARED_STRINGS: &str = "xl/sharedStrings.xml";
+const CALC_CHAIN: &str = "xl/calcChain.xml";
+/// Columns in a worksheet, `A` to `XFD`.
+const MAX_COLUMNS: u32 = 16_384;
+
+/// A template file and the anchor to fill.
+#[derive(Debug, Clone)]
+pub struct TemplateSpec {
+    pub path: PathBuf,
+    pub anchor: String,
+}
+
+/// Find template `name` (with or without `.xlsx`) in `dir`.
+pub fn resolve(dir: &Path, name: &str, anchor: Option<&str>) -> Result<TemplateSpec, AppError> {
+    let stem = name.strip_suffix(".xlsx").unwrap_or(name);
+    if stem.is_empty()
+        || !stem
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(AppError::BadRequest(format!(
+            "invalid template name '{}'",
+            name
+        )));
+    }
+    let path = dir.join(format!("{}.xlsx", stem));
+    if !path.is_file() {
+        return Err(AppError::NotFound(format!("template '{}'", stem)));
+    }
+    Ok(TemplateSpec {
+        path,
+        anchor: anchor.unwrap_or(DEFAULT_ANCHOR).to_string(),
+    })
+}
+
+/// Where the data region starts.
+struct Anchor {
+    sheet_name: String,
+    /// Zip entry of the worksheet, e.g. `xl/worksheets/sheet2.xml`.
+    sheet_part: String,
+    /// Zero-based.
+    row: u32,
+    col: u16,
+    /// Set when the anchor is a defined name, which is then resized to the data.
+    defined_name: Option<String>,
+}
+
+/// Streams export rows into a copy of a template.
+pub struct TemplateWriter {
+    archive: ZipArchive<BufReader<File>>,
+    out: ZipWriter<BufWriter<File>>,
+    anchor: Anchor,
+    /// Style index of the anchor cell, reused for the header.
+    header_style: Option<String>,
+    /// Sheet XML from `</sheetData>` on, written by `finish`.
+    tail: String,
+    next_row: u32,
+    columns: u16,
+}
+
+impl TemplateWriter {
+    pub fn create(spec: &TemplateSpec, output: &Path) -> Result<Self, AppError> {
+        let mut archive =
+            ZipArchive::new(BufReader::new(File::open(&spec.path)?)).map_err(zip_error)?;
+        let anchor = find_anchor(&mut archive, &spec.anchor)?;
+        let sheet = read_entry(&mut archive, &anchor.sheet_part)?;
+        let (head, header_style, tail) = split_sheet(&sheet, &anchor)?;
+
+        let mut out = ZipWriter::new(BufWriter::new(File::create(output)?));
+        out.start_file(anchor.sheet_part.as_str(), deflated())
+            .map_err(zip_error)?;
+        out.write_all(head.as_bytes())?;
+
+        Ok(TemplateWriter {
+            archive,
+            out,
+            next_row: anchor.row,
+            anchor,
+            header_style,
+            tail,
+            columns: 0,
+        })
+    }
+
+    pub fn write_header(&mut self, headers: &[String]) -> Result<(), AppError> {
+        let style = self.header_style.clone();
+        let cells: Vec<CellData> = headers.iter().cloned().map(CellData::Text).collect();
+        self.write_cells(&cells, style.as_deref())
+    }
+
+    pub fn write_row(&mut self, cells: &[CellData]) -> Result<(), AppError> {
+        self.write_cells(cells, None)
+    }
+
+    fn write_cells(&mut self, cells: &[CellData], style: Option<&str>) -> Result<(), AppError> {
+        let row = self.next_row + 1;
+        let mut xml = format!("<row r=\"{}\">", row);
+        for (i, cell) in cells.iter().enumerate() {
+            let reference = format!(
+                "{}{}",
+                column_number_to_name(self.anchor.col + i as u16),
+                row
+            );
+            let style = style.map(|s| format!(" s=\"{}\"", s)).unwrap_or_default();
+            match cell {
+                CellData::Empty => {
+                    if !style.is_empty() {
+                        xml.push_str(&format!("<c r=\"{}\"{}/>", reference, style));
+                    }
+                }
+                CellData::Int(v) => {
+                    xml.push_str(&format!("<c r=\"{}\"{}><v>{}</v></c>", reference, style, v))
+                }
+                CellData::Number(v) => {
+                    xml.push_str(&format!("<c r=\"{}\"{}><v>{}</v></c>", reference, style, v))
+                }
+                CellData::Text(s) => xml.push_str(&format!(
+                    "<c r=\"{}\"{} t=\"inlineStr\"><is><t xml:space=\"preserve\">{}</t></is></c>",
+                    reference,
+                    style,
+                    escape(s)
+                )),
+            }
+        }
+        xml.push_str("</row>");
+        self.out.write_all(xml.as_bytes())?;
+
+        self.columns = self.columns.max(cells.len() as u16);
+        self.next_row += 1;
+        Ok(())
+    }
+
+    /// Protect the data sheet, replacing any protection the template had.
+    /// Only that sheet is changed; other sheets keep the template's settings.
+    pub fn protect(&mut self, password: &str) {
+        let mut tail = String::with_capacity(self.tail.len());
+        let mut rest = self.tail.as_str();
+        while let Some(start) = rest.find("<sheetProtection") {
+            tail.push_str(&rest[..start]);
+            rest = match rest[start..].find("/>") {
+                Some(end) => &rest[start + end + 2..],
+                None => "",
+            };
+        }
+        tail.push_str(rest);
+
+        let pos = tail
+            .find("</sheetData>")
+            .map_or(0, |p| p + "</sheetData>".len());
+        tail.insert_str(pos, &protection::sheet_protection_xml(password));
+        self.tail = tail;
+    }
+
+    /// Close the data sheet and copy the rest of the template.
+    pub fn finish(mut self) -> Result<(), AppError> {
+        self.out.write_all(self.tail.as_bytes())?;
+
+        let data_range = format!(
+            "'{}'!${}${}:${}${}",
+            self.anchor.sheet_name.replace('\'', "''"),
+            column_number_to_name(self.anchor.col),
+            self.anchor.row + 1,
+            column_number_to_name(self.anchor.col + self.columns.max(1) - 1),
+            self.next_row.max(self.anchor.row + 1)
+        );
+
+        for index in 0..self.archive.len() {
+            let name = self
+                .archive
+                .by_index_raw(index)
+                .map_err(zip_error)?
+                .name()
+                .to_string();
+            match name.as_str() {
+                n if n == self.anchor.sheet_part => {}
+                // Cached formula order; Excel rebuilds it on load.
+                CALC_CHAIN => {}
+                WORKBOOK | WORKBOOK_RELS | CONTENT_TYPES => {
+                    let mut xml = read_entry(&mut self.archive, &name)?;
+                    if name == WORKBOOK {
+                        xml = patch_workbook(&xml, &self.anchor, &data_range);
+                    } else {
+                        xml = remove_elements(&xml, "calcChain.xml");
+                    }
+                    self.out.start_file(name, deflated()).map_err(zip_error)?;
+                    self.out.write_all(xml.as_bytes())?;
+                }
+                _ => {
+                    let entry = self.archive.by_index_raw(index).map_err(zip_error)?;
+                    self.out.raw_copy_file(entry).map_err(zip_error)?;
+                }
+            }
+        }
+
+        self.out.finish().map_err(zip_error)?.flush()?;
+        Ok(())
+    }
+}
+
+fn find_anchor(
+    archive: &mut ZipArchive<BufReader<File>>,
+    anchor: &str,
+) -> Result<Anchor, AppError> {
+    let workbook = read_entry(archive, WORKBOOK)?;
+    let rels = read_entry(archive, WORKBOOK_RELS)?;
+
+    let sheets: Vec<(String, String)> = elements(&workbook, "sheet")
+        .filter_map(|tag| {
+            let name = unescape(&attr(tag, "name")?);
+            let target = elements(&rels, "Relationship")
+                .find(|rel| attr(rel, "Id") == attr(tag, "r:id"))
+                .and_then(|rel| attr(rel, "Target"))?;
+            let part = match target.strip_prefix('/') {
+                Some(absolute) => absolute.to_string(),
+                None => format!("xl/{}", target),
+            };
+            Some((name, part))
+        })
+        .collect();
+
+    // A defined name wins over a marker cell.
+    for (tag, inner) in tags(&workbook, "definedName") {
+        if attr(tag, "name").as_deref() != Some(anchor) {
+            continue;
+        }
+        let reference = unescape(inner);
+        let (sheet, cell) = reference.rsplit_once('!').ok_or_else(|| {
+            AppError::BadRequest(format!("defined name '{}' is not a cell range", anchor))
+        })?;
+        let sheet_name = sheet.trim_matches('\'').replace("''", "'");
+        let (row, col) = parse_cell(cell.split(':').next().unwrap_or(cell))
+            .ok_or_else(|| AppError::BadRequest(format!("cannot parse '{}'", reference)))?;
+        let (_, sheet_part) = sheets
+            .iter()
+            .find(|(name, _)| *name == sheet_name)
+            .ok_or_else(|| AppError::BadRequest(format!("no sheet '{}'", sheet_name)))?;
+        return Ok(Anchor {
+            sheet_name,
+            sheet_part: sheet_part.clone(),
+            row,
+            col,
+            defined_name: Some(anchor.to_string()),
+        });
+    }
+
+    let marker = format!("{{{{{}}}}}", anchor);
+    let shared_index = if archive.by_name(SHARED_STRINGS).is_ok() {
+        let shared = read_entry(archive, SHARED_STRINGS)?;
+        let index = shared_strings(&shared).position(|s| s == marker);
+        index
+    } else {
+        None
+    };
+
+    for (sheet_name, sheet_part) in &sheets {
+        let xml = read_entry(archive, sheet_part)?;
+        for (tag, inner) in cells(&xml) {
+            let is_marker = match attr(tag, "t").as_deref() {
+                Some("s") => {
+                    shared_index.is_some()
+                        && text_between(inner, "<v>", "</v>").and_then(|v| v.parse().ok())
+                            == shared_index
+                }
+                Some("inlineStr") => inline_text(inner) == marker,
+                _ => false,
+            };
+            if is_marker {
+                let (row, col) = attr(tag, "r")
+                    .as_deref()
+                    .and_then(parse_cell)
+                    .ok_or_else(|| AppError::Excel("marker cell has no reference".to_string()))?;
+                return Ok(Anchor {
+                    sheet_name: sheet_name.clone(),
+                    sheet_part: sheet_part.clone(),
+                    row,
+                    col,
+                    defined_name: None,
+                });
+            }
+        }
+    }
+
+    Err(AppError::BadRequest(format!(
+        "template has no defined name '{}' and no cell containing '{}'",
+        anchor, marker
+    )))
+}
+
+/// Split the anchor sheet into the XML before the data rows (kept template
+/// rows included), the anchor cell's style and the XML after the rows.
+fn split_sheet(xml: &str, anchor: &Anchor) -> Result<(String, Option<String>, String), AppError> {
+    let missing = || AppError::Excel("template sheet has no <sheetData>".to_string());
+
+    // The dimension would be stale once rows are added; it is optional.
+    let xml = match elements(xml, "dimension").next() {
+        Some(tag) => xml.replacen(tag, "", 1),
+        None => xml.to_string(),
+    };
+
+    let (before, rows, after) = if let Some(pos) = xml.find("<sheetData/>") {
+        (&xml[..pos], "", &xml[pos + "<sheetData/>".len()..])
+    } else {
+        let open = xml.find("<sheetData").ok_or_else(missing)?;
+        let start = open + xml[open..].find('>').ok_or_else(missing)? + 1;
+        let end = xml.find("</sheetData>").ok_or_else(missing)?;
+        (
+            &xml[..open],
+            &xml[start..end],
+            &xml[end + "</sheetData>".len()..],
+        )
+    };
+
+    let mut head = format!("{}<sheetData>", before);
+    let mut header_style = None;
+    for row in split_rows(rows) {
+        let number: u32 = attr(row, "r").and_then(|r| r.parse().ok()).unwrap_or(0);
+        if number > 0 && number <= anchor.row {
+            head.push_str(row);
+        } else if number == anchor.row + 1 {
+            header_style = cells(row)
+                .find(|(tag, _)| {
+                    attr(tag, "r").as_deref().and_then(parse_cell) == Some((anchor.row, anchor.col))
+                })
+                .and_then(|(tag, _)| attr(tag, "s"));
+        }
+    }
+
+    Ok((head, header_style, format!("</sheetData>{}", after)))
+}
+
+fn patch_workbook(xml: &str, anchor: &Anchor, data_range: &str) -> String {
+    let mut xml = xml.to_string();
+
+    if let Some(name) = &anchor.defined_name {
+        let found = tags(&xml, "definedName")
+            .find(|(tag, _)| attr(tag, "name").as_deref() == Some(name))
+            .map(|(tag, inner)| format!("{}{}</definedName>", tag, inner));
+        if let Some(element) = found {
+            let tag = &element[..element.find('>').unwrap_or(0) + 1];
+            let resized = format!("{}{}</definedName>", tag, escape(data_range));
+            xml = xml.replacen(&element, &resized, 1);
+        }
+    }
+
+    // Cached formula results in the template are stale; recalculate on open.
+    let calc_pr = elements(&xml, "calcPr").next().map(str::to_string);
+    match calc_pr {
+        Some(tag) if !tag.contains("fullCalcOnLoad") => {
+            let patched = tag.replacen("<calcPr", "<calcPr fullCalcOnLoad=\"1\"", 1);
+            xml = xml.replacen(&tag, &patched, 1);
+        }
+        Some(_) => {}
+        None => {
+            let calc = "<calcPr fullCalcOnLoad=\"1\"/>";
+            let pos = xml
+                .find("</definedNames>")
+                .map(|p| p + "</definedNames>".len())
+                .or_else(|| xml.find("</sheets>").map(|p| p + "</sheets>".len()));
+            if let Some(pos) = pos {
+                xml.insert_str(pos, calc);
+            }
+        }
+    }
+
+    xml
+}
+
+/// Drop every empty element that mentions `needle` (used for the calc chain's
+/// relationship and content-type override).
+fn remove_elements(xml: &str, needle: &str) -> String {
+    let mut xml = xml.to_string();
+    for name in ["Relationship", "Override"] {
+        let tags: Vec<String> = elements(&xml, name)
+            .filter(|tag| tag.contains(needle))
+            .map(str::to_string)
+            .collect();
+        for tag in tags {
+            xml = xml.replacen(&tag, "", 1);
+        }
+    }
+    xml
+}
+
+fn read_entry(archive: &mut ZipArchive<BufReader<File>>, name: &str) -> Result<String, AppError> {
+    let mut xml = String::new();
+    archive
+        .by_name(name)
+        .map_err(zip_error)?
+        .read_to_string(&mut xml)?;
+    Ok(xml)
+}
+
+/// Opening tags `<name ...>` / `<name .../>` in `xml`.
+fn elements<'a>(xml: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    tags(xml, name).map(|(tag, _)| tag)
+}
+
+/// Opening tags of `name` with the inner XML up to the matching close tag
+/// (empty for `<name/>`). Elements of the same name must not nest.
+fn tags<'a>(xml: &'a str, name: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut pos = 0;
+    std::iter::from_fn(move || loop {
+        let start = pos + xml[pos..].find(&open)?;
+        let after = &xml[start + open.len()..];
+        let end = start + open.len() + after.find('>')? + 1;
+        pos = end;
+        if !matches!(after.chars().next(), Some(' ' | '>' | '/')) {
+            continue;
+        }
+        let tag = &xml[start..end];
+        if tag.ends_with("/>") {
+            return Some((tag, ""));
+        }
+        let inner_end = xml[end..].find(&close).map_or(end, |p| end + p);
+        return Some((tag, &xml[end..inner_end]));
+    })
+}
+
+/// `(opening tag, inner XML)` of each `<c>` cell.
+fn cells(xml: &str) -> impl Iterator<Item = (&str, &str)> {
+    tags(xml, "c")
+}
+
+fn split_rows(rows: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut rest = rows;
+    while let Some(start) = rest.find("<row") {
+        let open_end = match rest[start..].find('>') {
+            Some(p) => start + p + 1,
+            None => break,
+        };
+        let end = if rest[..open_end].ends_with("/>") {
+            open_end
+        } else {
+            match rest[open_end..].find("</row>") {
+                Some(p) => open_end + p + "</row>".len(),
+                None => break,
+            }
+        };
+        out.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    out
+}
+
+fn attr(tag: &str, name: &str) -> Option<String> {
+    let key = format!(" {}=\"", name);
+    let start = tag.find(&key)? + key.len();
+    let end = start + tag[start..].find('"')?;
+    Some(tag[start..end].to_string())
+}
+
+fn text_between<'a>(xml: &'a str, open: &str, close: &str) -> Option<&'a str> {
+    let start = xml.find(open)? + open.len();
+    let end = start + xml[start..].find(close)?;
+    Some(&xml[start..end])
+}
+
+/// Concatenated `<t>` text of a rich or plain string.
+fn inline_text(xml: &str) -> String {
+    tags(xml, "t").map(|(_, inner)| unescape(inner)).collect()
+}
+
+fn shared_strings(xml: &str) -> impl Iterator<Item = String> + '_ {
+    tags(xml, "si").map(|(_, inner)| inline_text(inner))
+}
+
+/// `$B$4` / `B4` to zero-based `(row, col)`.
+fn parse_cell(reference: &str) -> Option<(u32, u16)> {
+    let reference = reference.replace('$', "");
+    let split = reference.find(|c: char| c.is_ascii_digit())?;
+    let (letters, digits) = reference.split_at(split);
+    if letters.is_empty() {
+        return None;
+    }
+    let mut col: u32 = 0;
+    for c in letters.chars() {
+        if !c.is_ascii_alphabetic() {
+            return None;
+        }
+        col = col
+            .checked_mul(26)?
+            .checked_add(c.to_ascii_uppercase() as u32 - 'A' as u32 + 1)?;
+        if col > MAX_COLUMNS {
+            return None;
+        }
+    }
+    let row: u32 = digits.parse().ok()?;
+    (row > 0).then(|| (row - 1, (col - 1) as u16))
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            // Control characters are not allowed in XML 1.0.
+            c if (c as u32) < 0x20 && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn deflated() -> SimpleFileOptions {
+    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated)
+}
+
+fn zip_error(e: zip::result::ZipError) -> AppError {
+    AppError::Excel(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anchor(row: u32, col: u16) -> Anchor {
+        Anchor {
+            sheet_name: "Data".to_string(),
+            sheet_part: "xl/worksheets/sheet1.xml".to_string(),
+            row,
+            col,
+            defined_name: None,
+        }
+    }
+
+    #[test]
+    fn parses_cell_references() {
+        assert_eq!(parse_cell("A1"), Some((0, 0)));
+        assert_eq!(parse_cell("$B$4"), Some((3, 1)));
+        assert_eq!(parse_cell("b4"), Some((3, 1)));
+        assert_eq!(parse_cell("Z10"), Some((9, 25)));
+        assert_eq!(parse_cell("AA1"), Some((0, 26)));
+        assert_eq!(parse_cell("XFD1048576"), Some((1_048_575, 16_383)));
+
+        assert_eq!(parse_cell("A0"), None);
+        assert_eq!(parse_cell("4"), None);
+        assert_eq!(parse_cell("B"), None);
+        assert_eq!(parse_cell("B-4"), None);
+        assert_eq!(parse_cell("B4C"), None);
+        assert_eq!(parse_cell("Ä4"), None);
+        assert_eq!(parse_cell("XFD1"), Some((0, 16_383)));
+        assert_eq!(parse_cell("XFE1"), None);
+        assert_eq!(parse_cell("AAAAAAAAAA1"), None);
+        assert_eq!(parse_cell(&format!("{}1", "Z".repeat(40))), None);
+    }
+
+    #[test]
+    fn keeps_rows_above_the_anchor() {
+        let xml = concat!(
+            r#"<worksheet><dimension ref="A1:C5"/><sheetData>"#,
+            r#"<row r="1"><c r="A1" t="s"><v>0</v></c></row>"#,
+            r#"<row r="2"><c r="A2"/></row>"#,
+            r#"<row r="3"><c r="A3" s="2"/><c r="B3" s="7"><v>1</v></c></row>"#,
+            r#"<row r="4"><c r="B4"><v>2</v></c></row>"#,
+            r#"</sheetData><mergeCells count="0"/></worksheet>"#,
+        );
+        let (head, style, tail) = split_sheet(xml, &anchor(2, 1)).unwrap();
+        assert_eq!(
+            head,
+            concat!(
+                r#"<worksheet><sheetData>"#,
+                r#"<row r="1"><c r="A1" t="s"><v>0</v></c></row>"#,
+                r#"<row r="2"><c r="A2"/></row>"#,
+            )
+        );
+        assert_eq!(style.as_deref(), Some("7"));
+        assert_eq!(tail, r#"</sheetData><mergeCells count="0"/></worksheet>"#);
+    }
+
+    #[test]
+    fn splits_an_empty_sheet() {
+        let xml = r#"<worksheet><sheetFormatPr/><sheetData/><pageMargins/></worksheet>"#;
+        let (head, style, tail) = split_sheet(xml, &anchor(0, 0)).unwrap();
+        assert_eq!(head, "<worksheet><sheetFormatPr/><sheetData>");
+        assert_eq!(style, None);
+        assert_eq!(tail, "</sheetData><pageMargins/></worksheet>");
+    }
+
+    #[test]
+    fn rejects_a_sheet_without_data() {
+        assert!(split_sheet("<worksheet></worksheet>", &anchor(0, 0)).is_err());
+    }
+}
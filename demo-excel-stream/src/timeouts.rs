@@ -0,0 +1,186 @@
+//! Time limits for exports: Postgres `statement_timeout` and
+//! `idle_in_transaction_session_timeout` on the export transaction, and an
+//! overall deadline for the whole export.
+//!
+//! The deadline is enforced by Postgres too: each batch's statement timeout
+//! is cut to the time left, so a query that would overrun is cancelled on
+//! the server rather than abandoned on the client.
+
+use crate::error::AppError;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio_postgres::error::SqlState;
+
+/// Session limits for export transactions. `None` leaves the server's
+/// setting in place.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DbTimeouts {
+    pub statement: Option<Duration>,
+    pub idle_in_transaction: Option<Duration>,
+}
+
+impl DbTimeouts {
+    /// `SET LOCAL` statements for the current transaction, with the
+    /// statement timeout cut to `remaining` (the time left before the
+    /// export deadline). Empty when there is nothing to set.
+    pub fn set_local_sql(&self, remaining: Option<Duration>) -> String {
+        let statement = match (self.statement, remaining) {
+            (Some(limit), Some(left)) => Some(limit.min(left)),
+            (limit, left) => limit.or(left),
+        };
+        let mut sql = String::new();
+        if let Some(limit) = statement {
+            // 0 would mean "no limit"
+            sql.push_str(&format!(
+                "SET LOCAL statement_timeout = {};",
+                limit.as_millis().max(1)
+            ));
+        }
+        if let Some(limit) = self.idle_in_transaction {
+            sql.push_str(&format!(
+                "SET LOCAL idle_in_transaction_session_timeout = {};",
+                limit.as_millis().max(1)
+            ));
+        }
+        sql
+    }
+
+    /// Turn a failure caused by one of these limits, or by the deadline,
+    /// into [`AppError::Timeout`]; anything else stays a database error.
+    pub fn classify(&self, err: tokio_postgres::Error, deadline: &Deadline) -> AppError {
+        let Some(state) = err.code() else {
+            return AppError::Database(err);
+        };
+        if *state == SqlState::QUERY_CANCELED {
+            if deadline.passed() {
+                return deadline.error();
+            }
+            if let Some(limit) = self.statement {
+                return AppError::Timeout(format!(
+                    "a query ran longer than the statement timeout ({}s)",
+                    limit.as_secs_f64()
+                ));
+            }
+        }
+        if *state == SqlState::IDLE_IN_TRANSACTION_SESSION_TIMEOUT {
+            return AppError::Timeout(
+                "the export transaction sat idle past idle_in_transaction_session_timeout"
+                    .to_string(),
+            );
+        }
+        AppError::Database(err)
+    }
+}
+
+/// When an export must be finished by; `None` for no limit.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    limit: Option<Duration>,
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    /// A deadline `limit` from now.
+    pub fn after(limit: Option<Duration>) -> Self {
+        Deadline {
+            limit,
+            at: limit.map(|limit| Instant::now() + limit),
+        }
+    }
+
+    /// Time left; zero once passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn passed(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// [`AppError::Timeout`] if the deadline has passed.
+    pub fn check(&self) -> Result<(), AppError> {
+        if self.passed() {
+            Err(self.error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Await `work`, giving up with [`Deadline::error`] once the deadline
+    /// passes. Covers what Postgres cannot time: waiting for the connection,
+    /// retry pauses, and blocking work such as saving the file.
+    pub async fn run<T>(&self, work: impl Future<Output = T>) -> Result<T, AppError> {
+        match self.remaining() {
+            Some(left) => tokio::time::timeout(left, work)
+                .await
+                .map_err(|_| self.error()),
+            None => Ok(work.await),
+        }
+    }
+
+    pub fn error(&self) -> AppError {
+        AppError::Timeout(format!(
+            "the export did not finish within its deadline ({}s)",
+            self.limit.unwrap_or_default().as_secs()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: u64) -> Option<Duration> {
+        Some(Duration::from_secs(s))
+    }
+
+    #[test]
+    fn statement_timeout_is_cut_to_the_deadline() {
+        let timeouts = DbTimeouts {
+            statement: secs(30),
+            idle_in_transaction: secs(60),
+        };
+        assert_eq!(
+            timeouts.set_local_sql(None),
+            "SET LOCAL statement_timeout = 30000;\
+             SET LOCAL idle_in_transaction_session_timeout = 60000;"
+        );
+        assert!(timeouts
+            .set_local_sql(secs(5))
+            .starts_with("SET LOCAL statement_timeout = 5000;"));
+        assert!(timeouts
+            .set_local_sql(secs(300))
+            .starts_with("SET LOCAL statement_timeout = 30000;"));
+        // A passed deadline must not turn into "no limit".
+        assert!(timeouts
+            .set_local_sql(Some(Duration::ZERO))
+            .starts_with("SET LOCAL statement_timeout = 1;"));
+
+        let deadline_only = DbTimeouts::default();
+        assert_eq!(deadline_only.set_local_sql(None), "");
+        assert_eq!(
+            deadline_only.set_local_sql(secs(5)),
+            "SET LOCAL statement_timeout = 5000;"
+        );
+    }
+
+    #[tokio::test]
+    async fn deadline_stops_slow_work() {
+        let none = Deadline::after(None);
+        assert_eq!(none.remaining(), None);
+        assert!(none.check().is_ok());
+        assert_eq!(none.run(async { 1 }).await.unwrap(), 1);
+
+        let deadline = Deadline::after(Some(Duration::from_millis(20)));
+        assert!(deadline.check().is_ok());
+        let err = deadline
+            .run(tokio::time::sleep(Duration::from_secs(5)))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::Timeout(_)), "{:?}", err);
+        assert!(deadline.passed());
+        assert!(matches!(deadline.check(), Err(AppError::Timeout(_))));
+        assert_eq!(deadline.remaining(), Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,217 @@
+//! In-place edits of a finished `.xlsx` for features the streaming writer
+//! cannot express. The sheet part is rewritten as a stream, so patching never
+//! holds more than a small window of it in memory.
+
+use crate::error::AppError;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Bytes held back from the end of the sheet so `</worksheet>` can be found.
+const TAIL: usize = 4096;
+
+/// XML fragments to splice into one worksheet and the workbook part.
+#[derive(Debug, Default)]
+pub struct SheetPatch {
+    /// Inserted before `<sheetData>` (sheet views, column widths).
+    pub before_sheet_data: String,
+    /// Inserted after `</sheetData>` (sheet protection).
+    pub after_sheet_data: String,
+    /// Inserted before `</worksheet>` (autofilter).
+    pub before_end: String,
+    /// `<definedName>` elements added to `xl/workbook.xml`.
+    pub defined_names: String,
+}
+
+impl SheetPatch {
+    pub fn is_empty(&self) -> bool {
+        self.before_sheet_data.is_empty()
+            && self.after_sheet_data.is_empty()
+            && self.before_end.is_empty()
+            && self.defined_names.is_empty()
+    }
+}
+
+/// Apply `patch` to the worksheet part `sheet_part` (e.g.
+/// `xl/worksheets/sheet1.xml`) of the workbook at `path`. Every other entry is
+/// copied through without recompression.
+pub fn patch_sheet(path: &Path, sheet_part: &str, patch: &SheetPatch) -> Result<(), AppError> {
+    patch_sheets(path, &[(sheet_part.to_string(), patch)])
+}
+
+/// Apply several sheet patches in one pass over the workbook.
+pub fn patch_sheets(path: &Path, patches: &[(String, &SheetPatch)]) -> Result<(), AppError> {
+    let patches: Vec<(String, &SheetPatch)> = patches
+        .iter()
+        .filter(|(_, patch)| !patch.is_empty())
+        .cloned()
+        .collect();
+    if patches.is_empty() {
+        return Ok(());
+    }
+
+    let tmp_path = path.with_extension("xlsx.tmp");
+    let result = rewrite(path, &tmp_path, &patches);
+    match result {
+        Ok(()) => {
+            std::fs::rename(&tmp_path, path)?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            Err(e)
+        }
+    }
+}
+
+fn rewrite(
+    source: &Path,
+    target: &Path,
+    patches: &[(String, &SheetPatch)],
+) -> Result<(), AppError> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(source)?)).map_err(zip_error)?;
+    let mut out = ZipWriter::new(BufWriter::new(File::create(target)?));
+    let defined_names: String = patches
+        .iter()
+        .map(|(_, patch)| patch.defined_names.as_str())
+        .collect();
+    let mut found = 0;
+
+    for index in 0..archive.len() {
+        let name = archive
+            .by_index_raw(index)
+            .map_err(zip_error)?
+            .name()
+            .to_string();
+
+        if let Some((_, patch)) = patches.iter().find(|(part, _)| *part == name) {
+            let entry = archive.by_index(index).map_err(zip_error)?;
+            let options = SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Deflated)
+                .large_file(entry.size() > u32::MAX as u64 / 2);
+            out.start_file(name, options).map_err(zip_error)?;
+            rewrite_sheet(entry, &mut out, patch)?;
+            found += 1;
+        } else if name == "xl/workbook.xml" && !defined_names.is_empty() {
+            let mut xml = String::new();
+            archive
+                .by_index(index)
+                .map_err(zip_error)?
+                .read_to_string(&mut xml)?;
+            out.start_file(
+                name,
+                SimpleFileOptions::default().compression_method(CompressionMethod::Deflated),
+            )
+            .map_err(zip_error)?;
+            out.write_all(add_defined_names(&xml, &defined_names)?.as_bytes())?;
+        } else {
+            out.raw_copy_file(archive.by_index_raw(index).map_err(zip_error)?)
+                .map_err(zip_error)?;
+        }
+    }
+
+    if found < patches.len() {
+        let missing = patches
+            .iter()
+            .find(|(part, _)| archive.index_for_name(part).is_none())
+            .map_or("", |(part, _)| part.as_str());
+        return Err(AppError::Excel(format!("workbook has no part {}", missing)));
+    }
+    out.finish().map_err(zip_error)?.flush()?;
+    Ok(())
+}
+
+/// Copy one sheet part, inserting the patch fragments as they go past.
+fn rewrite_sheet(
+    mut reader: impl Read,
+    out: &mut impl Write,
+    patch: &SheetPatch,
+) -> Result<(), AppError> {
+    let mut buf = Vec::new();
+    let mut chunk = vec![0u8; 64 * 1024];
+
+    // Everything up to <sheetData, which always sits near the start.
+    loop {
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            return Err(AppError::Excel("worksheet has no <sheetData>".to_string()));
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        if let Some(pos) = find(&buf, b"<sheetData") {
+            out.write_all(&buf[..pos])?;
+            out.write_all(patch.before_sheet_data.as_bytes())?;
+            buf.drain(..pos);
+            break;
+        }
+    }
+
+    // The rows, keeping only the last TAIL bytes back.
+    loop {
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        if buf.len() > 2 * TAIL {
+            let flush = buf.len() - TAIL;
+            out.write_all(&buf[..flush])?;
+            buf.drain(..flush);
+        }
+    }
+
+    if !patch.after_sheet_data.is_empty() {
+        let pos = [&b"</sheetData>"[..], b"<sheetData/>"]
+            .iter()
+            .find_map(|tag| rfind(&buf, tag).map(|p| p + tag.len()))
+            .ok_or_else(|| AppError::Excel("worksheet has no </sheetData>".to_string()))?;
+        buf.splice(pos..pos, patch.after_sheet_data.bytes());
+    }
+
+    let end = rfind(&buf, b"</worksheet>")
+        .ok_or_else(|| AppError::Excel("worksheet has no </worksheet>".to_string()))?;
+    // Elements after </sheetData> have a fixed order; sheetProtection comes
+    // before autoFilter, so insert after it when present.
+    let insert_at = match rfind(&buf[..end], b"<sheetProtection") {
+        Some(start) => start + find(&buf[start..end], b">").map_or(0, |p| p + 1),
+        None => end,
+    };
+    out.write_all(&buf[..insert_at])?;
+    out.write_all(patch.before_end.as_bytes())?;
+    out.write_all(&buf[insert_at..])?;
+    Ok(())
+}
+
+fn add_defined_names(workbook_xml: &str, names: &str) -> Result<String, AppError> {
+    if let Some(pos) = workbook_xml.find("</definedNames>") {
+        return Ok(format!(
+            "{}{}{}",
+            &workbook_xml[..pos],
+            names,
+            &workbook_xml[pos..]
+        ));
+    }
+    let pos = workbook_xml
+        .find("</sheets>")
+        .ok_or_else(|| AppError::Excel("workbook has no </sheets>".to_string()))?
+        + "</sheets>".len();
+    Ok(format!(
+        "{}<definedNames>{}</definedNames>{}",
+        &workbook_xml[..pos],
+        names,
+        &workbook_xml[pos..]
+    ))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+fn zip_error(e: zip::result::ZipError) -> AppError {
+    AppError::Excel(e.to_string())
+}
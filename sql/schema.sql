@@ -1,31 +0,0 @@
--- PostgreSQL schema for orders table with 19 attributes
--- This table is designed for exporting 1 million records to Excel
-
-CREATE TABLE IF NOT EXISTS orders (
-    id SERIAL PRIMARY KEY,
-    order_number VARCHAR(50) NOT NULL UNIQUE,
-    customer_id INTEGER NOT NULL,
-    customer_name VARCHAR(100) NOT NULL,
-    customer_email VARCHAR(100) NOT NULL,
-    order_date DATE NOT NULL,
-    status VARCHAR(20) NOT NULL,
-    total_amount NUMERIC(10, 2) NOT NULL,
-    shipping_address VARCHAR(255) NOT NULL,
-    city VARCHAR(100) NOT NULL,
-    state VARCHAR(50) NOT NULL,
-    country VARCHAR(50) NOT NULL,
-    postal_code VARCHAR(20) NOT NULL,
-    payment_method VARCHAR(50) NOT NULL,
-    payment_status VARCHAR(20) NOT NULL,
-    shipping_method VARCHAR(50) NOT NULL,
-    tracking_number VARCHAR(100),
-    notes TEXT,
-    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-);
-
--- Create index on order_date for better query performance
-CREATE INDEX IF NOT EXISTS idx_orders_order_date ON orders(order_date);
-
--- Create index on customer_id for better query performance
-CREATE INDEX IF NOT EXISTS idx_orders_customer_id ON orders(customer_id);
-